@@ -0,0 +1,24 @@
+// Checked by test.sh using gdb, which has to walk through the frames of `outer` and `inner` to
+// get from `break_here` to `main`. There is no `.debug_frame`, so it uses the `.eh_frame` emitted
+// for them.
+
+#[inline(never)]
+fn break_here(x: u32) -> u32 {
+    unsafe { std::ptr::read_volatile(&x) }
+}
+
+#[inline(never)]
+fn inner(x: u32) -> u32 {
+    // Keep a callee-saved register alive across the call.
+    let y = x * 3;
+    break_here(x) + y
+}
+
+#[inline(never)]
+fn outer(x: u32) -> u32 {
+    inner(x) * 2
+}
+
+fn main() {
+    assert_eq!(outer(1), 8);
+}
@@ -0,0 +1,39 @@
+// Checked by test.sh using gdb. The value of the static can only be printed when its
+// `DW_AT_location` got relocated to the address of the static.
+
+static STATIC: u32 = 0x1234_5678;
+
+// Inlined into `main` by the MIR inliner with `-Zmir-opt-level=2`, which test.sh checks using
+// llvm-dwarfdump.
+#[inline]
+fn add_one(x: u32) -> u32 {
+    x + 1
+}
+
+fn main() {
+    // Keep the static alive.
+    assert_eq!(unsafe { std::ptr::read_volatile(&STATIC) }, 0x1234_5678);
+    assert_eq!(add_one(unsafe { std::ptr::read_volatile(&STATIC) }), 0x1234_5679);
+    assert_eq!(locals(7), 21);
+    assert_eq!(unsafe { std::ptr::read_volatile(&inner::NESTED) }, 0x8765_4321);
+    assert_eq!(inner::generic(42u64), 42);
+}
+
+// `arg` and `local` are SSA values with a location list, `on_stack` lives in a stack slot.
+#[inline(never)]
+fn locals(arg: u32) -> u32 {
+    let on_stack = [arg; 4];
+    let local = arg * 2;
+    local + on_stack[3]
+}
+
+// The static and the function are nested in `DW_TAG_namespace` entries for `debuginfo` and
+// `inner`, with the generic arguments only in the `DW_AT_name` of the function.
+mod inner {
+    pub static NESTED: u32 = 0x8765_4321;
+
+    #[inline(never)]
+    pub fn generic<T: Copy>(x: T) -> T {
+        unsafe { std::ptr::read_volatile(&x) }
+    }
+}
@@ -0,0 +1,7 @@
+// Run by test.sh in JIT mode with libstd loaded from its rlib. libstd gets the arguments from the
+// `argc` and `argv` passed to its `.init_array` function, not from `main`.
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    assert_eq!(args, ["abc", "b cd"]);
+}
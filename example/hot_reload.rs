@@ -0,0 +1,25 @@
+// test.sh runs this in lazy JIT mode and changes `VERSION` while it is running. `version` is only
+// translated again when the value of the constant is part of its hash.
+
+use std::time::{Duration, Instant};
+
+const VERSION: u32 = 1;
+
+fn version() -> u32 {
+    VERSION
+}
+
+fn main() {
+    let ready_file = std::env::args().nth(1).unwrap();
+
+    assert_eq!(version(), 1);
+    std::fs::write(ready_file, "").unwrap();
+
+    let start = Instant::now();
+    while version() == 1 {
+        assert!(start.elapsed() < Duration::from_secs(60), "hot reload didn't happen");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(version(), 2);
+    println!("hot reloaded");
+}
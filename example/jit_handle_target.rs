@@ -0,0 +1,19 @@
+// Compiled in JIT mode by `examples/jit_handle.rs`, which looks up and calls these functions.
+
+#[no_mangle]
+pub extern "C" fn add_one(x: u32) -> u32 {
+    x + 1
+}
+
+fn square(x: u32) -> u32 {
+    x * x
+}
+
+fn double<T: Copy + std::ops::Add<Output = T>>(x: T) -> T {
+    x + x
+}
+
+fn main() {
+    // Only functions used by the crate get codegened.
+    println!("{} {} {}", add_one(1), square(2), double(3u64));
+}
@@ -0,0 +1,40 @@
+// Functions which are first called on a spawned thread have to be translated on the thread
+// running the JIT in lazy JIT mode.
+
+use std::sync::mpsc;
+use std::thread;
+
+fn fib(n: u32) -> u64 {
+    if n < 2 {
+        n as u64
+    } else {
+        fib(n - 1) + fib(n - 2)
+    }
+}
+
+fn only_called_on_spawned_threads(id: u32) -> String {
+    format!("thread {}: fib({}) = {}", id, 10 + id, fib(10 + id))
+}
+
+fn main() {
+    let (tx, rx) = mpsc::channel();
+    let threads = (0..4)
+        .map(|id| {
+            let tx = tx.clone();
+            thread::spawn(move || tx.send(only_called_on_spawned_threads(id)).unwrap())
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    drop(tx);
+
+    let mut lines = rx.iter().collect::<Vec<_>>();
+    lines.sort();
+    assert_eq!(lines.len(), 4);
+    for line in lines {
+        println!("{}", line);
+    }
+
+    assert_eq!(fib(20), 6765);
+}
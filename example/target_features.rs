@@ -0,0 +1,30 @@
+// Checked by test.sh with `-Ctarget-cpu=nehalem -Ctarget-feature=-popcnt,+lzcnt,-ssse3`.
+
+// Both functions are always codegened because they are exported. Cranelift only uses the
+// `popcnt` and `lzcnt` instructions when the corresponding ISA flags are enabled.
+#[no_mangle]
+pub fn target_features_popcnt(x: u64) -> u32 {
+    x.count_ones()
+}
+
+#[no_mangle]
+pub fn target_features_lzcnt(x: u64) -> u32 {
+    x.leading_zeros()
+}
+
+fn main() {
+    // Enabled by default for x86_64.
+    assert!(cfg!(target_feature = "sse2"));
+    // Enabled by the cpu.
+    assert!(cfg!(target_feature = "sse3"));
+    // Disabled even though the cpu has it.
+    assert!(!cfg!(target_feature = "popcnt"));
+    // Enabled even though the cpu doesn't have it.
+    assert!(cfg!(target_feature = "lzcnt"));
+    // Disabling a feature also disables the features implying it.
+    assert!(!cfg!(target_feature = "ssse3"));
+    assert!(!cfg!(target_feature = "sse4.1"));
+    assert!(!cfg!(target_feature = "sse4.2"));
+
+    assert_eq!(target_features_popcnt(unsafe { std::ptr::read_volatile(&0xff) }), 8);
+}
@@ -0,0 +1,15 @@
+#[test]
+fn addition() {
+    assert_eq!(1 + 1, 2);
+}
+
+#[test]
+fn string_formatting() {
+    assert_eq!(format!("{}-{}", "abc", 42), "abc-42");
+}
+
+#[test]
+#[ignore]
+fn ignored() {
+    unreachable!();
+}
@@ -0,0 +1,81 @@
+//! Compile a crate in JIT mode as part of this process and call its functions using the lookup
+//! methods of `JitHandle`. The arguments are passed to rustc. test.sh runs it on
+//! `example/jit_handle_target.rs`.
+
+#![feature(rustc_private)]
+
+extern crate rustc;
+extern crate rustc_driver;
+extern crate rustc_interface;
+
+use rustc::hir::def_id::DefId;
+use rustc::ty::{Instance, ParamEnv, TyCtxt};
+
+use rustc_codegen_cranelift::{BackendConfig, CodegenMode, JitHandle};
+
+struct JitCallbacks;
+
+impl rustc_driver::Callbacks for JitCallbacks {
+    fn after_analysis(
+        &mut self,
+        compiler: &rustc_interface::interface::Compiler,
+    ) -> rustc_driver::Compilation {
+        compiler.session().abort_if_errors();
+
+        compiler.global_ctxt().unwrap().peek_mut().enter(|tcx| {
+            let backend_config = BackendConfig {
+                codegen_mode: CodegenMode::Jit,
+                ..BackendConfig::default()
+            };
+            let jit_handle = JitHandle::new(tcx, &backend_config);
+
+            // `#[no_mangle]` functions can be found by their name.
+            let add_one = jit_handle.lookup_symbol("add_one").unwrap();
+            let add_one: extern "C" fn(u32) -> u32 = unsafe { std::mem::transmute(add_one) };
+            assert_eq!(add_one(41), 42);
+
+            let square = jit_handle.lookup_def_id(fn_def_id(tcx, "square")).unwrap();
+            let square: fn(u32) -> u32 = unsafe { std::mem::transmute(square) };
+            assert_eq!(square(7), 49);
+
+            // Generic functions have to be looked up by instance instead.
+            let double_def_id = fn_def_id(tcx, "double");
+            assert!(jit_handle.lookup_def_id(double_def_id).is_none());
+            let substs = tcx.intern_substs(&[tcx.types.u64.into()]);
+            let double =
+                Instance::resolve(tcx, ParamEnv::reveal_all(), double_def_id, substs).unwrap();
+            let double = jit_handle.lookup_instance(double).unwrap();
+            let double: fn(u64) -> u64 = unsafe { std::mem::transmute(double) };
+            assert_eq!(double(21), 42);
+
+            // Instances which weren't codegened can't be found.
+            let substs = tcx.intern_substs(&[tcx.types.u8.into()]);
+            let double =
+                Instance::resolve(tcx, ParamEnv::reveal_all(), double_def_id, substs).unwrap();
+            assert!(jit_handle.lookup_instance(double).is_none());
+        });
+
+        println!("All lookups succeeded");
+        rustc_driver::Compilation::Stop
+    }
+}
+
+fn fn_def_id(tcx: TyCtxt<'_>, name: &str) -> DefId {
+    let item = tcx
+        .hir()
+        .krate()
+        .items
+        .values()
+        .find(|item| &*item.ident.as_str() == name)
+        .unwrap_or_else(|| panic!("no item named `{}`", name));
+    tcx.hir().local_def_id(item.hir_id)
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    let res = rustc_driver::catch_fatal_errors(|| {
+        rustc_driver::run_compiler(&args, &mut JitCallbacks, None, None)
+    })
+    .and_then(|res| res);
+    std::process::exit(if res.is_ok() { 0 } else { 1 });
+}
@@ -5,6 +5,8 @@ mod returning;
 
 use rustc_target::spec::abi::Abi;
 
+use cranelift::codegen::ir::ValueLabel;
+
 use self::pass_mode::*;
 use crate::prelude::*;
 
@@ -299,6 +301,9 @@ pub fn codegen_fn_prelude(fx: &mut FunctionCx<'_, '_, impl Backend>, start_ebb:
                         // of this argument, to prevent a copy.
 
                         let place = CPlace::for_addr(addr, val.layout());
+                        // Track the pointer, so debuginfo can describe the local by
+                        // dereferencing it.
+                        fx.bcx.set_val_label(addr, ValueLabel::from_u32(local.as_u32()));
 
                         #[cfg(debug_assertions)]
                         self::comments::add_local_place_comments(fx, place, local);
@@ -95,5 +95,6 @@ pub fn codegen_inner(sess: &Session, module: &mut Module<impl Backend + 'static>
             bcx.finalize();
         }
         module.define_function(func_id, &mut ctx).unwrap();
+        crate::perf_map::record_function(&caller_name, &ctx, module.isa());
     }
 }
@@ -7,20 +7,36 @@ pub fn trans_fn<'clif, 'tcx, B: Backend + 'static>(
     instance: Instance<'tcx>,
     linkage: Linkage,
 ) {
+    let (name, _sig) = get_function_name_and_sig(cx.tcx, instance, false);
+    trans_fn_with_symbol(cx, instance, &name, linkage);
+}
+
+/// Translate `instance`, but define it under the given symbol name instead of its own. This is
+/// used by the lazy JIT, where the symbol name of the function is already taken by its stub.
+pub fn trans_fn_with_symbol<'clif, 'tcx, B: Backend + 'static>(
+    cx: &mut crate::CodegenCx<'clif, 'tcx, B>,
+    instance: Instance<'tcx>,
+    name: &str,
+    linkage: Linkage,
+) -> FuncId {
     let tcx = cx.tcx;
 
     let mir = tcx.instance_mir(instance.def);
 
     // Declare function
-    let (name, sig) = get_function_name_and_sig(tcx, instance, false);
-    let func_id = cx.module.declare_function(&name, linkage, &sig).unwrap();
+    let (_, sig) = get_function_name_and_sig(tcx, instance, false);
+    let func_id = cx.module.declare_function(name, linkage, &sig).unwrap();
     let mut debug_context = cx
         .debug_context
         .as_mut()
-        .map(|debug_context| FunctionDebugContext::new(tcx, debug_context, mir, &name, &sig));
+        .map(|debug_context| FunctionDebugContext::new(tcx, debug_context, mir, instance, name));
 
     // Make FunctionBuilder
     let mut func = Function::with_name_signature(ExternalName::user(0, 0), sig);
+    if debug_context.is_some() {
+        // Track which values belong to which local, so variable locations can be emitted.
+        func.collect_debug_info();
+    }
     let mut func_ctx = FunctionBuilderContext::new();
     let mut bcx = FunctionBuilder::new(&mut func, &mut func_ctx);
 
@@ -60,9 +76,20 @@ pub fn trans_fn<'clif, 'tcx, B: Backend + 'static>(
     let instance = fx.instance;
     let clif_comments = fx.clif_comments;
     let source_info_set = fx.source_info_set;
-
-    #[cfg(debug_assertions)]
-    crate::pretty_clif::write_clif_file(cx.tcx, "unopt", instance, &func, &clif_comments, None);
+    let local_map = fx.local_map;
+
+    let dump_clif = cx.backend_config.should_dump_clif(name);
+    if dump_clif {
+        crate::pretty_clif::write_clif_file(
+            cx.tcx,
+            cx.backend_config,
+            "unopt",
+            instance,
+            &func,
+            &clif_comments,
+            None,
+        );
+    }
 
     // Verify function
     verify_func(tcx, &clif_comments, &func);
@@ -77,24 +104,48 @@ pub fn trans_fn<'clif, 'tcx, B: Backend + 'static>(
         .expect("value location ranges");
 
     // Write optimized function to file for debugging
-    #[cfg(debug_assertions)]
-    crate::pretty_clif::write_clif_file(
-        cx.tcx,
-        "opt",
-        instance,
-        &context.func,
-        &clif_comments,
-        Some(&value_ranges),
+    if dump_clif {
+        crate::pretty_clif::write_clif_file(
+            cx.tcx,
+            cx.backend_config,
+            "opt",
+            instance,
+            &context.func,
+            &clif_comments,
+            Some(&value_ranges),
+        );
+    }
+
+    crate::perf_map::record_function_with_lines(
+        tcx,
+        name,
+        context,
+        cx.module.isa(),
+        &source_info_set,
+        mir.span,
     );
 
-    // Define debuginfo for function
     let isa = cx.module.isa();
-    debug_context
-        .as_mut()
-        .map(|x| x.define(tcx, context, isa, &source_info_set));
+    if let Some(unwind_context) = &mut cx.unwind_context {
+        unwind_context.add_function(name, context, isa);
+    }
+
+    // Define debuginfo for function
+    debug_context.as_mut().map(|x| {
+        x.define(
+            tcx,
+            context,
+            isa,
+            &source_info_set,
+            &local_map,
+            &value_ranges,
+        )
+    });
 
     // Clear context to make it usable for the next function
     context.clear();
+
+    func_id
 }
 
 fn verify_func(tcx: TyCtxt, writer: &crate::pretty_clif::CommentWriter, func: &Function) {
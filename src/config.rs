@@ -0,0 +1,342 @@
+//! Configuration of the backend. It is passed as `key=value` pairs using `-Cllvm-args`, for
+//! example `-Cllvm-args="mode=jit jit-arg=--verbose"`. Values containing spaces have to be quoted
+//! like in a shell, for example `jit-arg='a b'`. As rustc splits the llvm args on whitespace
+//! before the backend sees them, consecutive whitespace inside quotes becomes a single space.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::perf_map::PerfMode;
+use crate::prelude::*;
+
+const VALID_KEYS: &[&str] = &[
+    "mode",
+    "jit-arg",
+    "jit-perf",
+    "jit-reload-trigger",
+    "clif-dump-dir",
+    "clif-dump-filter",
+    "verifier",
+    "opt-level",
+    "incr-cache",
+    "dwarf-version",
+    "debuginfo-compression",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CodegenMode {
+    /// Write object files and link them like any other backend.
+    Aot,
+    /// Translate all functions of an executable and run it in-process.
+    Jit,
+    /// Like `Jit`, but translate functions on their first call.
+    JitLazy,
+}
+
+impl FromStr for CodegenMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aot" => Ok(CodegenMode::Aot),
+            "jit" => Ok(CodegenMode::Jit),
+            "lazy-jit" => Ok(CodegenMode::JitLazy),
+            _ => Err(format!(
+                "Unknown codegen mode `{}`, expected `aot`, `jit` or `lazy-jit`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OptLevel {
+    /// No optimizations. Used for `-Copt-level=0`.
+    Fastest,
+    /// Cheap optimizations like GVN.
+    Default,
+    /// All optimizations Cranelift has, including LICM.
+    Best,
+}
+
+impl OptLevel {
+    /// The value of the `opt_level` Cranelift setting.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OptLevel::Fastest => "fastest",
+            OptLevel::Default => "default",
+            OptLevel::Best => "best",
+        }
+    }
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fastest" => Ok(OptLevel::Fastest),
+            "default" => Ok(OptLevel::Default),
+            "best" => Ok(OptLevel::Best),
+            _ => Err(format!(
+                "Unknown value `{}` for `opt-level`, expected `fastest`, `default` or `best`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BackendConfig {
+    /// `mode=aot|jit|lazy-jit`, defaults to `aot`. The JIT modes only apply to executables.
+    pub codegen_mode: CodegenMode,
+
+    /// `jit-arg=<arg>`, may be repeated. The arguments passed to the JIT-compiled program after
+    /// the program name.
+    pub jit_args: Vec<String>,
+
+    /// `jit-perf=map|jitdump`. Write the files necessary to profile JIT-compiled code with `perf`.
+    pub jit_perf: Option<PerfMode>,
+
+    /// `jit-reload-trigger=<path>`. Only for `mode=lazy-jit`. Recompile the crate whenever the
+    /// modification time of this file changes and hot reload the changed functions.
+    pub jit_reload_trigger: Option<PathBuf>,
+
+    /// `clif-dump-dir=<path>`. Write the clif ir of every function before and after optimization
+    /// to this directory.
+    pub clif_dump_dir: Option<PathBuf>,
+
+    /// `clif-dump-filter=<str>`. Only dump the clif ir of functions whose symbol name contains
+    /// this string.
+    pub clif_dump_filter: Option<String>,
+
+    /// `verifier=true|false`. Run the Cranelift verifier after every pass. Defaults to `true` for
+    /// debug builds of the backend.
+    pub enable_verifier: bool,
+
+    /// `opt-level=fastest|default|best`. The Cranelift optimization level. Defaults to the level
+    /// corresponding to `-Copt-level`.
+    pub opt_level: Option<OptLevel>,
+
+    /// `incr-cache=true|false`. Store the object files of codegen units in the incremental cache
+    /// and reuse them when the codegen unit didn't change. Defaults to `true`.
+    pub enable_incr_cache: bool,
+
+    /// `dwarf-version=3|4|5`. The DWARF version of the debuginfo. Defaults to 3 on macOS and 4
+    /// everywhere else. Version 2 isn't supported, as it has no `DW_AT_ranges`, which the
+    /// compilation unit and lexical blocks need.
+    pub dwarf_version: Option<u16>,
+
+    /// `debuginfo-compression=none|zlib`. Compress the debug sections of object files like
+    /// `--compress-debug-sections=zlib` does. Only supported for ELF.
+    pub compress_debuginfo: bool,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            codegen_mode: CodegenMode::Aot,
+            jit_args: Vec::new(),
+            jit_perf: None,
+            jit_reload_trigger: None,
+            clif_dump_dir: None,
+            clif_dump_filter: None,
+            enable_verifier: cfg!(debug_assertions),
+            opt_level: None,
+            enable_incr_cache: true,
+            dwarf_version: None,
+            compress_debuginfo: false,
+        }
+    }
+}
+
+impl BackendConfig {
+    /// Parse the config from `-Cllvm-args`. Invalid options are reported as errors.
+    pub fn from_opts(sess: &Session) -> Self {
+        let mut config = BackendConfig::default();
+
+        for opt in &split_args(&sess.opts.cg.llvm_args.join(" ")) {
+            let mut parts = opt.splitn(2, '=');
+            let key = parts.next().unwrap();
+            let value = match parts.next() {
+                Some(value) => value,
+                None => {
+                    sess.err(&format!(
+                        "Expected `key=value` in -Cllvm-args, found `{}`",
+                        opt
+                    ));
+                    continue;
+                }
+            };
+
+            let res = match key {
+                "mode" => value.parse().map(|mode| config.codegen_mode = mode),
+                "jit-arg" => {
+                    config.jit_args.push(value.to_string());
+                    Ok(())
+                }
+                "jit-perf" => match value {
+                    "map" => Ok(Some(PerfMode::Map)),
+                    "jitdump" => Ok(Some(PerfMode::JitDump)),
+                    _ => Err(format!(
+                        "Unknown value `{}` for `jit-perf`, expected `map` or `jitdump`",
+                        value
+                    )),
+                }
+                .map(|perf_mode| config.jit_perf = perf_mode),
+                "jit-reload-trigger" => {
+                    config.jit_reload_trigger = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                "clif-dump-dir" => {
+                    config.clif_dump_dir = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                "clif-dump-filter" => {
+                    config.clif_dump_filter = Some(value.to_string());
+                    Ok(())
+                }
+                "verifier" => value
+                    .parse()
+                    .map(|enable| config.enable_verifier = enable)
+                    .map_err(|_| {
+                        format!(
+                            "Invalid value `{}` for `verifier`, expected `true` or `false`",
+                            value
+                        )
+                    }),
+                "opt-level" => value
+                    .parse()
+                    .map(|opt_level| config.opt_level = Some(opt_level)),
+                "incr-cache" => value
+                    .parse()
+                    .map(|enable| config.enable_incr_cache = enable)
+                    .map_err(|_| {
+                        format!(
+                            "Invalid value `{}` for `incr-cache`, expected `true` or `false`",
+                            value
+                        )
+                    }),
+                "dwarf-version" => match value.parse::<u16>() {
+                    Ok(version @ 3..=5) => Ok(version),
+                    _ => Err(format!(
+                        "Invalid value `{}` for `dwarf-version`, expected 3, 4 or 5",
+                        value
+                    )),
+                }
+                .map(|version| config.dwarf_version = Some(version)),
+                "debuginfo-compression" => match value {
+                    "none" => Ok(false),
+                    "zlib" => Ok(true),
+                    _ => Err(format!(
+                        "Unknown value `{}` for `debuginfo-compression`, expected `none` or `zlib`",
+                        value
+                    )),
+                }
+                .map(|compress| config.compress_debuginfo = compress),
+                _ => Err(format!(
+                    "Unknown option `{}` in -Cllvm-args, valid options are: {}",
+                    key,
+                    VALID_KEYS.join(", ")
+                )),
+            };
+
+            if let Err(err) = res {
+                sess.err(&err);
+            }
+        }
+
+        if config.jit_reload_trigger.is_some() && config.codegen_mode != CodegenMode::JitLazy {
+            sess.err("`jit-reload-trigger` requires `mode=lazy-jit`");
+        }
+
+        if config.opt_level.is_none() {
+            use rustc::session::config::OptLevel as RustcOptLevel;
+
+            if let RustcOptLevel::Size | RustcOptLevel::SizeMin = sess.opts.optimize {
+                sess.warn("Cranelift can't optimize for size, using `opt-level=default` instead");
+            }
+        }
+
+        config
+    }
+
+    /// The Cranelift optimization level, either the one given by `opt-level` or the one
+    /// corresponding to `-Copt-level`.
+    pub fn opt_level(&self, sess: &Session) -> OptLevel {
+        use rustc::session::config::OptLevel as RustcOptLevel;
+
+        self.opt_level.unwrap_or(match sess.opts.optimize {
+            RustcOptLevel::No => OptLevel::Fastest,
+            RustcOptLevel::Less | RustcOptLevel::Default => OptLevel::Default,
+            RustcOptLevel::Aggressive => OptLevel::Best,
+            // Cranelift has no optimization level targeting code size, so use the default passes.
+            RustcOptLevel::Size | RustcOptLevel::SizeMin => OptLevel::Default,
+        })
+    }
+
+    /// The DWARF version to emit, either the one given by `dwarf-version` or the default of the
+    /// target.
+    pub fn dwarf_version(&self, sess: &Session) -> u16 {
+        self.dwarf_version.unwrap_or_else(|| {
+            if sess.target.target.options.is_like_osx {
+                3
+            } else {
+                4
+            }
+        })
+    }
+
+    /// Whether the clif ir of the function with the given symbol name should be written to the
+    /// `clif_dump_dir`.
+    pub fn should_dump_clif(&self, symbol_name: &str) -> bool {
+        self.clif_dump_dir.is_some()
+            && self
+                .clif_dump_filter
+                .as_ref()
+                .map(|filter| symbol_name.contains(&**filter))
+                .unwrap_or(true)
+    }
+}
+
+/// Split the llvm args into options like a shell would, so values containing whitespace can be
+/// passed by quoting them.
+fn split_args(args: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => arg.push(c),
+            (_, '\\') => {
+                if let Some(c) = chars.next() {
+                    arg.push(c);
+                }
+                in_arg = true;
+            }
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    split.push(std::mem::replace(&mut arg, String::new()));
+                    in_arg = false;
+                }
+            }
+            (_, c) => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        split.push(arg);
+    }
+
+    split
+}
@@ -16,6 +16,9 @@ use crate::prelude::*;
 pub struct ConstantCx {
     todo: HashSet<TodoItem>,
     done: HashSet<DataId>,
+    /// Prepended to the names of allocations. Only used when hot reloading in JIT mode, as the
+    /// `AllocId`s of different compilation sessions overlap.
+    alloc_name_prefix: String,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
@@ -25,11 +28,17 @@ enum TodoItem {
 }
 
 impl ConstantCx {
-    pub fn finalize(mut self, tcx: TyCtxt<'_>, module: &mut Module<impl Backend>) {
+    pub fn with_alloc_name_prefix(alloc_name_prefix: String) -> Self {
+        ConstantCx {
+            alloc_name_prefix,
+            ..ConstantCx::default()
+        }
+    }
+
+    pub fn finalize(&mut self, tcx: TyCtxt<'_>, module: &mut Module<impl Backend>) {
         //println!("todo {:?}", self.todo);
-        define_all_allocs(tcx, module, &mut self);
+        define_all_allocs(tcx, module, self);
         //println!("done {:?}", self.done);
-        self.done.clear();
     }
 }
 
@@ -180,18 +189,19 @@ fn trans_const_place<'tcx>(
     //println!("const value: {:?} allocation: {:?}", value, alloc);
     let alloc_id = fx.tcx.alloc_map.lock().create_memory_alloc(alloc);
     fx.constants_cx.todo.insert(TodoItem::Alloc(alloc_id));
-    let data_id = data_id_for_alloc_id(fx.module, alloc_id, alloc.align);
+    let data_id = data_id_for_alloc_id(fx.module, fx.constants_cx, alloc_id, alloc.align);
     cplace_for_dataid(fx, const_.ty, data_id)
 }
 
 fn data_id_for_alloc_id<B: Backend>(
     module: &mut Module<B>,
+    cx: &ConstantCx,
     alloc_id: AllocId,
     align: Align,
 ) -> DataId {
     module
         .declare_data(
-            &format!("__alloc_{}", alloc_id.0),
+            &format!("{}__alloc_{}", cx.alloc_name_prefix, alloc_id.0),
             Linkage::Local,
             false,
             Some(align.bytes() as u8),
@@ -275,7 +285,7 @@ fn define_all_allocs(tcx: TyCtxt<'_>, module: &mut Module<impl Backend>, cx: &mu
             TodoItem::Alloc(alloc_id) => {
                 //println!("alloc_id {}", alloc_id);
                 let alloc = memory.get(alloc_id).unwrap();
-                let data_id = data_id_for_alloc_id(module, alloc_id, alloc.align);
+                let data_id = data_id_for_alloc_id(module, cx, alloc_id, alloc.align);
                 (data_id, alloc)
             }
             TodoItem::Static(def_id) => {
@@ -349,7 +359,7 @@ fn define_all_allocs(tcx: TyCtxt<'_>, module: &mut Module<impl Backend>, cx: &mu
                 }
                 GlobalAlloc::Memory(_) => {
                     cx.todo.insert(TodoItem::Alloc(reloc));
-                    data_id_for_alloc_id(module, reloc, alloc.align)
+                    data_id_for_alloc_id(module, cx, reloc, alloc.align)
                 }
                 GlobalAlloc::Static(def_id) => {
                     // Don't push a `TodoItem::Static` here, as it will cause statics used by
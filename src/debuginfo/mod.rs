@@ -0,0 +1,1048 @@
+mod scopes;
+mod types;
+mod unwind;
+
+pub use unwind::UnwindContext;
+
+use crate::prelude::*;
+
+use rustc::hir::CodegenFnAttrFlags;
+use rustc::ty::DefIdTree;
+use rustc_index::vec::IndexVec;
+use rustc_codegen_ssa::debuginfo::type_names::compute_debuginfo_type_name;
+
+use std::path::PathBuf;
+
+use syntax::source_map::{FileName, FilePathMapping};
+use syntax_pos::hygiene;
+
+use cranelift::codegen::ir::{Opcode, StackSlots, ValueLabel, ValueLoc};
+use cranelift::codegen::isa::{RegUnit, TargetIsa};
+use cranelift::codegen::ValueLabelsRanges;
+
+use gimli::write::{
+    Address, AttributeValue, DwarfUnit, EndianVec, Expression, FileId, LineProgram, LineString,
+    LineStringTable, Location, LocationList, Range, RangeList, Result, Sections, UnitEntryId,
+    Writer,
+};
+use gimli::{Encoding, Format, LineEncoding, RunTimeEndian, SectionId};
+
+use faerie::*;
+
+fn target_endian(tcx: TyCtxt) -> RunTimeEndian {
+    use rustc::ty::layout::Endian;
+
+    match tcx.data_layout.endian {
+        Endian::Big => RunTimeEndian::Big,
+        Endian::Little => RunTimeEndian::Little,
+    }
+}
+
+/// Cranelift always uses `rbp` as frame pointer on x86_64. After the prologue it points 16 bytes
+/// below the CFA, which is where stack slot offsets are relative to.
+const X86_64_RBP: u16 = 6;
+const X86_64_RBP_CFA_OFFSET: i64 = 16;
+const X86_64_RSP: u16 = 7;
+/// The return address column used in call frame information.
+const X86_64_RA: u16 = 16;
+
+fn is_x86_64(isa: &dyn TargetIsa) -> bool {
+    isa.triple().architecture == target_lexicon::Architecture::X86_64
+}
+
+/// Map a Cranelift register to its DWARF register number. Only x86_64 is supported for now.
+fn dwarf_register(isa: &dyn TargetIsa, reg: RegUnit) -> Option<u16> {
+    if !is_x86_64(isa) {
+        return None;
+    }
+
+    let name = isa.register_info().display_regunit(reg).to_string();
+    let name = name.trim_start_matches('%');
+    Some(match name {
+        "rax" => 0,
+        "rdx" => 1,
+        "rcx" => 2,
+        "rbx" => 3,
+        "rsi" => 4,
+        "rdi" => 5,
+        "rbp" => 6,
+        "rsp" => 7,
+        _ if name.starts_with("xmm") => 17 + name[3..].parse::<u16>().ok()?,
+        _ if name.starts_with('r') => name[1..].parse::<u16>().ok()?,
+        _ => return None,
+    })
+}
+
+/// A DWARF expression for a value stored at `loc`. Stack slots are addressed relative to the
+/// frame base, which is `rbp`.
+fn location_expression(
+    isa: &dyn TargetIsa,
+    stack_slots: &StackSlots,
+    loc: ValueLoc,
+) -> Option<Expression> {
+    let mut expr = Vec::new();
+    match loc {
+        ValueLoc::Reg(reg) => {
+            let reg = dwarf_register(isa, reg)?;
+            if reg < 32 {
+                expr.push(gimli::DW_OP_reg0.0 + reg as u8);
+            } else {
+                expr.push(gimli::DW_OP_regx.0);
+                gimli::leb128::write::unsigned(&mut expr, u64::from(reg)).unwrap();
+            }
+        }
+        ValueLoc::Stack(stack_slot) => {
+            if !is_x86_64(isa) {
+                return None;
+            }
+            let offset = stack_slots[stack_slot].offset?;
+            expr.push(gimli::DW_OP_fbreg.0);
+            gimli::leb128::write::signed(&mut expr, i64::from(offset) + X86_64_RBP_CFA_OFFSET)
+                .unwrap();
+        }
+        ValueLoc::Unassigned => return None,
+    }
+    Some(Expression(expr))
+}
+
+/// Like `location_expression`, but for a value which is a pointer to the actual location. The
+/// result is a memory location described relative to the register or frame slot of the pointer.
+fn pointee_location_expression(
+    isa: &dyn TargetIsa,
+    stack_slots: &StackSlots,
+    loc: ValueLoc,
+) -> Option<Expression> {
+    let mut expr = Vec::new();
+    match loc {
+        ValueLoc::Reg(reg) => {
+            let reg = dwarf_register(isa, reg)?;
+            if reg < 32 {
+                expr.push(gimli::DW_OP_breg0.0 + reg as u8);
+            } else {
+                expr.push(gimli::DW_OP_bregx.0);
+                gimli::leb128::write::unsigned(&mut expr, u64::from(reg)).unwrap();
+            }
+            gimli::leb128::write::signed(&mut expr, 0).unwrap();
+        }
+        ValueLoc::Stack(_) => {
+            expr = location_expression(isa, stack_slots, loc)?.0;
+            expr.push(gimli::DW_OP_deref.0);
+        }
+        ValueLoc::Unassigned => return None,
+    }
+    Some(Expression(expr))
+}
+
+/// The first instruction of the epilogue Cranelift inserted before the return at the end of `ebb`.
+fn epilogue_start(func: &Function, ebb: Ebb) -> Option<Inst> {
+    let last_inst = func.layout.last_inst(ebb)?;
+    if !func.dfg[last_inst].opcode().is_return() {
+        return None;
+    }
+
+    let mut start = last_inst;
+    while let Some(prev_inst) = func.layout.prev_inst(start) {
+        match func.dfg[prev_inst].opcode() {
+            Opcode::X86Pop | Opcode::AdjustSpUpImm => start = prev_inst,
+            _ => break,
+        }
+    }
+    Some(start)
+}
+
+/// The paths of real files have already been remapped using `--remap-path-prefix` by the source
+/// map, just like the working directory used as `DW_AT_comp_dir`. Doctests keep their original
+/// path, so they are remapped here. Other files, like `<macros>`, get their display name in the
+/// compilation directory.
+fn line_program_add_file(
+    line_program: &mut LineProgram,
+    line_strings: &mut LineStringTable,
+    path_mapping: &FilePathMapping,
+    file: &FileName,
+) -> FileId {
+    let path = match file {
+        FileName::Real(path) => path.clone(),
+        FileName::DocTest(path, _) => path_mapping.map_prefix(path.clone()).0,
+        _ => PathBuf::from(file.to_string()),
+    };
+
+    let dir_id = match path.parent() {
+        Some(dir_name) if !dir_name.as_os_str().is_empty() => {
+            let dir_name = LineString::new(
+                dir_name.to_string_lossy().into_owned().into_bytes(),
+                line_program.encoding(),
+                line_strings,
+            );
+            line_program.add_directory(dir_name)
+        }
+        _ => line_program.default_directory(),
+    };
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => path.to_string_lossy().into_owned(),
+    };
+    let file_name = LineString::new(file_name.into_bytes(), line_program.encoding(), line_strings);
+    line_program.add_file(file_name, dir_id, None)
+}
+
+/// `R_X86_64_PC32`. faerie only knows about absolute relocations for debug sections.
+const R_X86_64_PC32: u32 = 2;
+
+#[derive(Clone)]
+struct DebugReloc {
+    offset: u32,
+    size: u8,
+    name: DebugRelocName,
+    addend: i64,
+    kind: DebugRelocKind,
+}
+
+#[derive(Clone)]
+enum DebugRelocName {
+    Section(SectionId),
+    Symbol(usize),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum DebugRelocKind {
+    /// The address of the target.
+    Absolute,
+    /// The address of the target relative to the location of the relocation. Only used for the
+    /// pointers in `.eh_frame`.
+    PcRelative,
+}
+
+impl DebugReloc {
+    fn name<'a>(&self, symbols: &'a indexmap::IndexSet<String>) -> &'a str {
+        match self.name {
+            DebugRelocName::Section(id) => id.name(),
+            DebugRelocName::Symbol(index) => symbols.get_index(index).unwrap(),
+        }
+    }
+
+    fn faerie_reloc(&self) -> faerie::Reloc {
+        match self.kind {
+            DebugRelocKind::Absolute => faerie::Reloc::Debug {
+                size: self.size,
+                addend: self.addend as i32,
+            },
+            DebugRelocKind::PcRelative => {
+                assert_eq!(self.size, 4);
+                faerie::Reloc::Raw {
+                    reloc: R_X86_64_PC32,
+                    addend: self.addend as i32,
+                }
+            }
+        }
+    }
+}
+
+pub struct DebugContext<'tcx> {
+    endian: RunTimeEndian,
+    symbols: indexmap::IndexSet<String>,
+    compress_sections: bool,
+
+    dwarf: DwarfUnit,
+    unit_range_list: RangeList,
+
+    tcx: TyCtxt<'tcx>,
+    types: FxHashMap<Ty<'tcx>, UnitEntryId>,
+    namespaces: FxHashMap<DefId, UnitEntryId>,
+    abstract_subprograms: FxHashMap<DefId, UnitEntryId>,
+}
+
+impl<'tcx> DebugContext<'tcx> {
+    /// DWARF version 5 puts the strings of the line program in `.debug_line_str` and uses
+    /// `.debug_rnglists` and `.debug_loclists` instead of `.debug_ranges` and `.debug_loc`.
+    pub fn new(tcx: TyCtxt<'tcx>, address_size: u8, backend_config: &BackendConfig) -> Self {
+        let encoding = Encoding {
+            format: Format::Dwarf32,
+            version: backend_config.dwarf_version(tcx.sess),
+            address_size,
+        };
+
+        let mut dwarf = DwarfUnit::new(encoding);
+
+        // FIXME: how to get version when building out of tree?
+        // Normally this would use option_env!("CFG_VERSION").
+        let producer = format!("cranelift fn (rustc version {})", "unknown version");
+        let comp_dir = tcx.sess.working_dir.0.to_string_lossy().into_owned();
+        let name = match tcx.sess.local_crate_source_file {
+            Some(ref path) => path.to_string_lossy().into_owned(),
+            None => tcx.crate_name(LOCAL_CRATE).to_string(),
+        };
+
+        let line_program = LineProgram::new(
+            encoding,
+            LineEncoding::default(),
+            LineString::new(comp_dir.as_bytes(), encoding, &mut dwarf.line_strings),
+            LineString::new(name.as_bytes(), encoding, &mut dwarf.line_strings),
+            None,
+        );
+        dwarf.unit.line_program = line_program;
+
+        {
+            let name = dwarf.strings.add(&*name);
+            let comp_dir = dwarf.strings.add(&*comp_dir);
+
+            let root = dwarf.unit.root();
+            let root = dwarf.unit.get_mut(root);
+            root.set(
+                gimli::DW_AT_producer,
+                AttributeValue::StringRef(dwarf.strings.add(producer)),
+            );
+            root.set(
+                gimli::DW_AT_language,
+                AttributeValue::Language(gimli::DW_LANG_Rust),
+            );
+            root.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+            root.set(gimli::DW_AT_comp_dir, AttributeValue::StringRef(comp_dir));
+            root.set(
+                gimli::DW_AT_low_pc,
+                AttributeValue::Address(Address::Constant(0)),
+            );
+        }
+
+        let compress_sections = backend_config.compress_debuginfo
+            && crate::target_triple(tcx.sess).binary_format == target_lexicon::BinaryFormat::Elf;
+
+        DebugContext {
+            endian: target_endian(tcx),
+            symbols: indexmap::IndexSet::new(),
+            compress_sections,
+
+            dwarf,
+            unit_range_list: RangeList(Vec::new()),
+
+            tcx,
+            types: FxHashMap::default(),
+            namespaces: FxHashMap::default(),
+            abstract_subprograms: FxHashMap::default(),
+        }
+    }
+
+    /// `DW_FORM_exprloc` only exists since DWARF 4. Before that, expressions are blocks.
+    fn expression_value(&self, expr: Expression) -> AttributeValue {
+        if self.dwarf.unit.encoding().version >= 4 {
+            AttributeValue::Exprloc(expr)
+        } else {
+            AttributeValue::Block(expr.0)
+        }
+    }
+
+    /// gimli can't write relocatable addresses inside of expressions yet. Instead the operand of
+    /// `DW_OP_addr` is the index of `symbol`, which `relocate_addr_expressions` turns into a
+    /// relocation after the entries have been written.
+    fn addr_expression(&self, symbol: usize) -> Expression {
+        let mut expr = EndianVec::new(self.endian);
+        expr.write_u8(gimli::DW_OP_addr.0).unwrap();
+        expr.write_udata(symbol as u64, self.dwarf.unit.encoding().address_size)
+            .unwrap();
+        Expression(expr.take())
+    }
+
+    /// Find the expressions written by `addr_expression` in `.debug_info` and replace the symbol
+    /// index operands with relocations. Only statics use `DW_OP_addr`, and always as the whole
+    /// `DW_AT_location`, so walking the entries finds all of them.
+    fn relocate_addr_expressions(
+        &self,
+        sections: &mut Sections<WriterRelocate>,
+    ) -> gimli::read::Result<()> {
+        use gimli::read::Reader;
+
+        let address_size = self.dwarf.unit.encoding().address_size;
+        let mut operands = Vec::new();
+        {
+            let debug_info_data = sections.debug_info.writer.slice();
+            let debug_info = gimli::read::DebugInfo::new(debug_info_data, self.endian);
+            let debug_abbrev =
+                gimli::read::DebugAbbrev::new(sections.debug_abbrev.writer.slice(), self.endian);
+
+            let mut units = debug_info.units();
+            while let Some(unit) = units.next()? {
+                let abbrevs = unit.abbreviations(&debug_abbrev)?;
+                let mut entries = unit.entries(&abbrevs);
+                while let Some((_, entry)) = entries.next_dfs()? {
+                    let mut expr = match entry.attr_value(gimli::DW_AT_location)? {
+                        Some(gimli::read::AttributeValue::Exprloc(expr)) => expr.0,
+                        _ => continue,
+                    };
+                    if expr.is_empty() || expr.read_u8()? != gimli::DW_OP_addr.0 {
+                        continue;
+                    }
+                    let offset = expr.slice().as_ptr() as usize - debug_info_data.as_ptr() as usize;
+                    let symbol = expr.read_address(address_size)?;
+                    operands.push((offset, symbol as usize));
+                }
+            }
+        }
+
+        let debug_info = &mut sections.debug_info;
+        for (offset, symbol) in operands {
+            debug_info
+                .writer
+                .write_udata_at(offset, 0, address_size)
+                .unwrap();
+            debug_info.relocs.push(DebugReloc {
+                offset: offset as u32,
+                size: address_size,
+                name: DebugRelocName::Symbol(symbol),
+                addend: 0,
+                kind: DebugRelocKind::Absolute,
+            });
+        }
+        Ok(())
+    }
+
+    /// Get the `DW_TAG_namespace` for the module, impl or function `def_id`, nested in the
+    /// namespaces of its parents. Crate roots become a namespace named after the crate.
+    fn item_namespace(&mut self, def_id: DefId) -> UnitEntryId {
+        if let Some(&namespace_id) = self.namespaces.get(&def_id) {
+            return namespace_id;
+        }
+
+        let (parent_id, name) = match self.tcx.parent(def_id) {
+            Some(parent) => (
+                self.item_namespace(parent),
+                self.tcx.def_key(def_id).disambiguated_data.data.to_string(),
+            ),
+            None => (
+                self.dwarf.unit.root(),
+                self.tcx.crate_name(def_id.krate).to_string(),
+            ),
+        };
+
+        let namespace_id = self.dwarf.unit.add(parent_id, gimli::DW_TAG_namespace);
+        let name_id = self.dwarf.strings.add(name);
+        let namespace_entry = self.dwarf.unit.get_mut(namespace_id);
+        namespace_entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name_id));
+        self.namespaces.insert(def_id, namespace_id);
+        namespace_id
+    }
+
+    /// Add a `DW_TAG_variable` for the static `def_id`, which is defined in this unit.
+    pub fn define_static(&mut self, def_id: DefId) {
+        let tcx = self.tcx;
+
+        // FIXME thread locals need `DW_OP_GNU_push_tls_address`, but aren't supported yet anyway
+        if tcx.is_foreign_item(def_id)
+            || tcx
+                .codegen_fn_attrs(def_id)
+                .flags
+                .contains(CodegenFnAttrFlags::THREAD_LOCAL)
+        {
+            return;
+        }
+
+        let instance = Instance::mono(tcx, def_id);
+        let symbol_name = tcx.symbol_name(instance).name.as_str();
+        let (symbol, _) = self.symbols.insert_full(symbol_name.to_string());
+
+        let scope = match tcx.parent(def_id) {
+            Some(parent) => self.item_namespace(parent),
+            None => self.dwarf.unit.root(),
+        };
+        let type_id = self.dwarf_ty(instance.ty(tcx));
+        let location = self.expression_value(self.addr_expression(symbol));
+
+        let var_id =
+            self.new_named_entry(scope, gimli::DW_TAG_variable, &tcx.item_name(def_id).as_str());
+        let linkage_name_id = self.dwarf.strings.add(&*symbol_name);
+        let var_entry = self.dwarf.unit.get_mut(var_id);
+        var_entry.set(
+            gimli::DW_AT_linkage_name,
+            AttributeValue::StringRef(linkage_name_id),
+        );
+        var_entry.set(gimli::DW_AT_type, AttributeValue::ThisUnitEntryRef(type_id));
+        var_entry.set(
+            gimli::DW_AT_external,
+            AttributeValue::Flag(tcx.is_reachable_non_generic(def_id)),
+        );
+        var_entry.set(gimli::DW_AT_location, location);
+
+        self.emit_location(tcx, var_id, tcx.def_span(def_id));
+    }
+
+    fn emit_location(&mut self, tcx: TyCtxt<'tcx>, entry_id: UnitEntryId, span: Span) {
+        let loc = tcx.sess.source_map().lookup_char_pos(span.lo());
+
+        let file_id = line_program_add_file(
+            &mut self.dwarf.unit.line_program,
+            &mut self.dwarf.line_strings,
+            tcx.sess.source_map().path_mapping(),
+            &loc.file.name,
+        );
+
+        let entry = self.dwarf.unit.get_mut(entry_id);
+
+        entry.set(
+            gimli::DW_AT_decl_file,
+            AttributeValue::FileIndex(Some(file_id)),
+        );
+        entry.set(
+            gimli::DW_AT_decl_line,
+            AttributeValue::Udata(loc.line as u64),
+        );
+        // FIXME: probably omit this
+        entry.set(
+            gimli::DW_AT_decl_column,
+            AttributeValue::Udata(loc.col.to_usize() as u64),
+        );
+    }
+
+    fn set_unit_ranges(&mut self) {
+        let unit_range_list_id = self.dwarf.unit.ranges.add(self.unit_range_list.clone());
+        let root = self.dwarf.unit.root();
+        let root = self.dwarf.unit.get_mut(root);
+        root.set(
+            gimli::DW_AT_ranges,
+            AttributeValue::RangeListRef(unit_range_list_id),
+        );
+    }
+
+    /// The symbol name and code size of every function defined so far.
+    pub fn functions(&self) -> Vec<(&str, u64)> {
+        self.unit_range_list
+            .0
+            .iter()
+            .filter_map(|range| match *range {
+                Range::StartLength {
+                    begin: Address::Symbol { symbol, addend: 0 },
+                    length,
+                } => Some((&**self.symbols.get_index(symbol).unwrap(), length)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Write all debug sections for code that has already been loaded into memory, like in JIT
+    /// mode. References to symbols are resolved to the addresses returned by `lookup_symbol`.
+    /// References between sections are resolved to offsets within the target section.
+    pub fn emit_in_memory(
+        &mut self,
+        lookup_symbol: impl Fn(&str) -> Option<u64>,
+    ) -> Vec<(SectionId, Vec<u8>)> {
+        self.set_unit_ranges();
+
+        let mut sections = Sections::new(WriterRelocate::new(self.endian));
+        self.dwarf.write(&mut sections).unwrap();
+        self.relocate_addr_expressions(&mut sections).unwrap();
+
+        let mut emitted = Vec::new();
+        let _: Result<()> = sections.for_each_mut(|id, section| {
+            if section.writer.slice().is_empty() {
+                return Ok(());
+            }
+            for reloc in &section.relocs {
+                let value = match reloc.name {
+                    DebugRelocName::Section(_) => reloc.addend as u64,
+                    DebugRelocName::Symbol(_) => match lookup_symbol(reloc.name(&self.symbols)) {
+                        Some(addr) => addr.wrapping_add(reloc.addend as u64),
+                        None => continue,
+                    },
+                };
+                section
+                    .writer
+                    .write_udata_at(reloc.offset as usize, value, reloc.size)?;
+            }
+            emitted.push((id, section.writer.take()));
+            Ok(())
+        });
+        emitted
+    }
+
+    /// Returns the names of the sections which were compressed, as they need the `SHF_COMPRESSED`
+    /// flag. See `elf::ElfFixups`.
+    pub fn emit(&mut self, artifact: &mut Artifact) -> Vec<String> {
+        self.set_unit_ranges();
+
+        let mut sections = Sections::new(WriterRelocate::new(self.endian));
+        self.dwarf.write(&mut sections).unwrap();
+        self.relocate_addr_expressions(&mut sections).unwrap();
+
+        let mut compressed_sections = Vec::new();
+        let _: Result<()> = sections.for_each_mut(|id, section| {
+            if !section.writer.slice().is_empty() {
+                let data = section.writer.take();
+                let data = match self.compress_section(id, &data) {
+                    Some(compressed) => {
+                        compressed_sections.push(id.name().to_string());
+                        compressed
+                    }
+                    None => data,
+                };
+                artifact
+                    .declare_with(id.name(), Decl::section(SectionKind::Debug), data)
+                    .unwrap();
+            }
+            Ok(())
+        });
+
+        let _: Result<()> = sections.for_each(|id, section| {
+            for reloc in &section.relocs {
+                artifact
+                    .link_with(
+                        faerie::Link {
+                            from: id.name(),
+                            to: reloc.name(&self.symbols),
+                            at: u64::from(reloc.offset),
+                        },
+                        reloc.faerie_reloc(),
+                    )
+                    .expect("faerie relocation error");
+            }
+            Ok(())
+        });
+
+        compressed_sections
+    }
+
+    /// Compress a section in the `SHF_COMPRESSED` format of `--compress-debug-sections=zlib`,
+    /// which is an `Elf32_Chdr` or `Elf64_Chdr` compression header followed by a zlib stream.
+    /// Relocations still refer to offsets in the uncompressed data. Returns `None` when the section
+    /// shouldn't be compressed, or compression wouldn't make it smaller.
+    fn compress_section(&self, id: SectionId, data: &[u8]) -> Option<Vec<u8>> {
+        use flate2::write::ZlibEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        const ELFCOMPRESS_ZLIB: u32 = 1;
+
+        if !self.compress_sections || !id.name().starts_with(".debug_") {
+            return None;
+        }
+
+        let mut header = EndianVec::new(self.endian);
+        header.write_u32(ELFCOMPRESS_ZLIB).unwrap();
+        if self.dwarf.unit.encoding().address_size == 8 {
+            header.write_u32(0).unwrap(); // ch_reserved
+            header.write_u64(data.len() as u64).unwrap();
+            header.write_u64(1).unwrap(); // ch_addralign
+        } else {
+            header.write_u32(data.len() as u32).unwrap();
+            header.write_u32(1).unwrap(); // ch_addralign
+        }
+
+        let mut encoder = ZlibEncoder::new(header.into_vec(), Compression::default());
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        if compressed.len() < data.len() {
+            Some(compressed)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct FunctionDebugContext<'a, 'tcx> {
+    debug_context: &'a mut DebugContext<'tcx>,
+    entry_id: UnitEntryId,
+    symbol: usize,
+    mir: &'tcx Body<'tcx>,
+}
+
+impl<'a, 'tcx> FunctionDebugContext<'a, 'tcx> {
+    pub fn new(
+        tcx: TyCtxt<'tcx>,
+        debug_context: &'a mut DebugContext<'tcx>,
+        mir: &'tcx Body<'tcx>,
+        instance: Instance<'tcx>,
+        name: &str,
+    ) -> Self {
+        let (symbol, _) = debug_context.symbols.insert_full(name.to_string());
+
+        let def_id = instance.def_id();
+        let scope = match tcx.parent(def_id) {
+            Some(parent) => debug_context.item_namespace(parent),
+            None => debug_context.dwarf.unit.root(),
+        };
+
+        // Render generic arguments like the LLVM backend, eg. `foo<u8, alloc::string::String>`.
+        let mut plain_name = tcx.def_key(def_id).disambiguated_data.data.to_string();
+        if !tcx.is_closure(def_id) {
+            let generic_args = instance
+                .substs
+                .types()
+                .map(|ty| compute_debuginfo_type_name(tcx, ty, true))
+                .collect::<Vec<_>>();
+            if !generic_args.is_empty() {
+                plain_name.push('<');
+                plain_name.push_str(&generic_args.join(", "));
+                plain_name.push('>');
+            }
+        }
+
+        let entry_id = debug_context
+            .dwarf
+            .unit
+            .add(scope, gimli::DW_TAG_subprogram);
+        let linkage_name_id = debug_context.dwarf.strings.add(name);
+        let plain_name_id = debug_context.dwarf.strings.add(plain_name);
+        let entry = debug_context.dwarf.unit.get_mut(entry_id);
+        entry.set(
+            gimli::DW_AT_linkage_name,
+            AttributeValue::StringRef(linkage_name_id),
+        );
+        entry.set(gimli::DW_AT_name, AttributeValue::StringRef(plain_name_id));
+
+        entry.set(
+            gimli::DW_AT_low_pc,
+            AttributeValue::Address(Address::Symbol { symbol, addend: 0 }),
+        );
+
+        let return_ty = tcx.subst_and_normalize_erasing_regions(
+            instance.substs,
+            ParamEnv::reveal_all(),
+            &mir.return_ty(),
+        );
+        if !return_ty.is_unit() {
+            let return_type_id = debug_context.dwarf_ty(return_ty);
+            let entry = debug_context.dwarf.unit.get_mut(entry_id);
+            entry.set(gimli::DW_AT_type, AttributeValue::ThisUnitEntryRef(return_type_id));
+        }
+
+        debug_context.emit_location(tcx, entry_id, mir.span);
+
+        FunctionDebugContext {
+            debug_context,
+            entry_id,
+            symbol,
+            mir,
+        }
+    }
+
+    pub fn define(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        context: &Context,
+        isa: &dyn TargetIsa,
+        source_info_set: &indexmap::IndexSet<SourceInfo>,
+        local_map: &HashMap<Local, CPlace<'tcx>>,
+        value_labels_ranges: &ValueLabelsRanges,
+    ) {
+        let line_program = &mut self.debug_context.dwarf.unit.line_program;
+
+        line_program.begin_sequence(Some(Address::Symbol {
+            symbol: self.symbol,
+            addend: 0,
+        }));
+
+        let encinfo = isa.encoding_info();
+        let func = &context.func;
+        let mut ebbs = func.layout.ebbs().collect::<Vec<_>>();
+        ebbs.sort_by_key(|ebb| func.offsets[*ebb]); // Ensure inst offsets always increase
+
+        let line_strings = &mut self.debug_context.dwarf.line_strings;
+        let mir_ctxt = self.mir.span.ctxt();
+        let mut lookup_span = |line_program: &mut LineProgram, span: Span| {
+            // Attribute code expanded from macros to the macro invocation in this function.
+            let span = hygiene::walk_chain(span, mir_ctxt);
+            let source_map = tcx.sess.source_map();
+            let loc = source_map.lookup_char_pos(span.lo());
+            let file_id = line_program_add_file(
+                line_program,
+                line_strings,
+                source_map.path_mapping(),
+                &loc.file.name,
+            );
+            (file_id, loc.line as u64, loc.col.to_u32() as u64 + 1)
+        };
+
+        // Rows are only generated when the location changes, which is at the start of a MIR
+        // statement or terminator. Instructions without a location, like the prologue inserted by
+        // Cranelift and the setup of arguments and locals, belong to the previous row or the
+        // start of the function. The first row with a location ends the prologue.
+        let mut scope_ranges = IndexVec::from_elem(Vec::new(), &self.mir.source_scopes);
+        let mut last_row = None;
+        let mut in_prologue = true;
+        let mut end = 0;
+        for ebb in ebbs {
+            let epilogue_start = epilogue_start(func, ebb);
+            for (offset, inst, size) in func.inst_offsets(ebb, &encinfo) {
+                let srcloc = func.srclocs[inst];
+                let row = if !srcloc.is_default() {
+                    let source_info = *source_info_set.get_index(srcloc.bits() as usize).unwrap();
+                    scope_ranges[source_info.scope].push((offset, offset + size));
+                    lookup_span(line_program, source_info.span)
+                } else {
+                    match last_row {
+                        Some(row) => row,
+                        None => lookup_span(line_program, self.mir.span),
+                    }
+                };
+                let prologue_end = in_prologue && !srcloc.is_default();
+                let epilogue_begin = epilogue_start == Some(inst);
+
+                if last_row != Some(row) || prologue_end || epilogue_begin {
+                    let (file_id, line, column) = row;
+                    // Only a new line is a new statement for stepping in a debugger.
+                    let is_statement = match last_row {
+                        Some((last_file_id, last_line, _)) => {
+                            last_file_id != file_id || last_line != line
+                        }
+                        None => true,
+                    };
+                    line_program.row().address_offset = offset as u64;
+                    line_program.row().file = file_id;
+                    line_program.row().line = line;
+                    line_program.row().column = column;
+                    line_program.row().is_statement = is_statement || prologue_end;
+                    line_program.row().prologue_end = prologue_end;
+                    line_program.row().epilogue_begin = epilogue_begin;
+                    line_program.generate_row();
+                    last_row = Some(row);
+                }
+
+                if prologue_end {
+                    in_prologue = false;
+                }
+                end = offset + size;
+            }
+        }
+
+        line_program.end_sequence(end as u64);
+
+        // Before DWARF 4, `DW_AT_high_pc` can only be an address, not an offset from the low pc.
+        let high_pc = if self.debug_context.dwarf.unit.encoding().version >= 4 {
+            AttributeValue::Udata(end as u64)
+        } else {
+            AttributeValue::Address(Address::Symbol {
+                symbol: self.symbol,
+                addend: end as i64,
+            })
+        };
+        let entry = self.debug_context.dwarf.unit.get_mut(self.entry_id);
+        entry.set(gimli::DW_AT_high_pc, high_pc);
+
+        self.debug_context
+            .unit_range_list
+            .0
+            .push(Range::StartLength {
+                begin: Address::Symbol {
+                    symbol: self.symbol,
+                    addend: 0,
+                },
+                length: end as u64,
+            });
+
+        if is_x86_64(isa) {
+            let frame_base = self
+                .debug_context
+                .expression_value(Expression(vec![gimli::DW_OP_reg0.0 + X86_64_RBP as u8]));
+            let entry = self.debug_context.dwarf.unit.get_mut(self.entry_id);
+            entry.set(gimli::DW_AT_frame_base, frame_base);
+        }
+
+        let scope_entries = self.define_scopes(scope_ranges);
+
+        for local in self.mir.args_iter().chain(self.mir.vars_and_temps_iter()) {
+            if let Some(&place) = local_map.get(&local) {
+                let scope_id = scope_entries[self.mir.local_decls[local].visibility_scope];
+                self.define_local(context, isa, scope_id, local, place, value_labels_ranges);
+            }
+        }
+    }
+
+    /// Add a `DW_TAG_formal_parameter` or `DW_TAG_variable` for `local` if it has a user visible
+    /// name. SSA locals get a location list built from the ranges in which Cranelift assigned a
+    /// register or spill slot to one of their values. Stack slots have a fixed location. Locals
+    /// backed by the memory of a by-ref argument are found by dereferencing the argument pointer.
+    fn define_local(
+        &mut self,
+        context: &Context,
+        isa: &dyn TargetIsa,
+        scope_id: UnitEntryId,
+        local: Local,
+        place: CPlace<'tcx>,
+        value_labels_ranges: &ValueLabelsRanges,
+    ) {
+        let local_decl = &self.mir.local_decls[local];
+        let name = match local_decl.name {
+            Some(name) => name,
+            None => return,
+        };
+
+        let tag = if local.index() <= self.mir.arg_count {
+            gimli::DW_TAG_formal_parameter
+        } else {
+            gimli::DW_TAG_variable
+        };
+        let type_id = self.debug_context.dwarf_ty(place.layout().ty);
+        let var_id = self.debug_context.dwarf.unit.add(scope_id, tag);
+        let name_id = self.debug_context.dwarf.strings.add(&*name.as_str());
+
+        let location = match *place.inner() {
+            CPlaceInner::Var(_) => self.location_list(local, value_labels_ranges, |loc| {
+                location_expression(isa, &context.func.stack_slots, loc)
+            }),
+            // The pointer is labeled with the local, see `abi::codegen_fn_prelude`.
+            CPlaceInner::Addr(_, None) => self.location_list(local, value_labels_ranges, |loc| {
+                pointee_location_expression(isa, &context.func.stack_slots, loc)
+            }),
+            CPlaceInner::Stack(stack_slot) => {
+                location_expression(isa, &context.func.stack_slots, ValueLoc::Stack(stack_slot))
+                    .map(|expr| self.debug_context.expression_value(expr))
+            }
+            CPlaceInner::Addr(_, Some(_)) | CPlaceInner::NoPlace => None,
+        };
+
+        let var_entry = self.debug_context.dwarf.unit.get_mut(var_id);
+        var_entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name_id));
+        var_entry.set(gimli::DW_AT_type, AttributeValue::ThisUnitEntryRef(type_id));
+        if let Some(location) = location {
+            var_entry.set(gimli::DW_AT_location, location);
+        }
+
+        let tcx = self.debug_context.tcx;
+        self.debug_context
+            .emit_location(tcx, var_id, local_decl.source_info.span);
+    }
+
+    /// A location list for the values labeled with `local`, using `expression` to describe where
+    /// Cranelift put them.
+    fn location_list(
+        &mut self,
+        local: Local,
+        value_labels_ranges: &ValueLabelsRanges,
+        expression: impl Fn(ValueLoc) -> Option<Expression>,
+    ) -> Option<AttributeValue> {
+        let ranges = value_labels_ranges
+            .get(&ValueLabel::from_u32(local.as_u32()))
+            .map(|ranges| &**ranges)
+            .unwrap_or(&[]);
+        let locations = ranges
+            .iter()
+            .filter_map(|range| {
+                Some(Location::StartEnd {
+                    begin: Address::Symbol {
+                        symbol: self.symbol,
+                        addend: i64::from(range.start),
+                    },
+                    end: Address::Symbol {
+                        symbol: self.symbol,
+                        addend: i64::from(range.end),
+                    },
+                    data: expression(range.loc)?,
+                })
+            })
+            .collect::<Vec<_>>();
+        if locations.is_empty() {
+            return None;
+        }
+
+        let list_id = self
+            .debug_context
+            .dwarf
+            .unit
+            .locations
+            .add(LocationList(locations));
+        Some(AttributeValue::LocationListRef(list_id))
+    }
+}
+
+#[derive(Clone)]
+struct WriterRelocate {
+    relocs: Vec<DebugReloc>,
+    writer: EndianVec<RunTimeEndian>,
+}
+
+impl WriterRelocate {
+    fn new(endian: RunTimeEndian) -> Self {
+        WriterRelocate {
+            relocs: Vec::new(),
+            writer: EndianVec::new(endian),
+        }
+    }
+}
+
+impl Writer for WriterRelocate {
+    type Endian = RunTimeEndian;
+
+    fn endian(&self) -> Self::Endian {
+        self.writer.endian()
+    }
+
+    fn len(&self) -> usize {
+        self.writer.len()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.writer.write(bytes)
+    }
+
+    fn write_at(&mut self, offset: usize, bytes: &[u8]) -> Result<()> {
+        self.writer.write_at(offset, bytes)
+    }
+
+    fn write_address(&mut self, address: Address, size: u8) -> Result<()> {
+        match address {
+            Address::Constant(val) => self.write_udata(val, size),
+            Address::Symbol { symbol, addend } => {
+                let offset = self.len() as u64;
+                self.relocs.push(DebugReloc {
+                    offset: offset as u32,
+                    size,
+                    name: DebugRelocName::Symbol(symbol),
+                    addend: addend as i64,
+                    kind: DebugRelocKind::Absolute,
+                });
+                self.write_udata(0, size)
+            }
+        }
+    }
+
+    /// Pointers to functions in `.eh_frame` are pc-relative, so the section doesn't need dynamic
+    /// relocations and the linker can build `.eh_frame_hdr` from it.
+    fn write_eh_pointer(&mut self, address: Address, eh_pe: gimli::DwEhPe, size: u8) -> Result<()> {
+        match address {
+            Address::Constant(val) if eh_pe.application() == gimli::DW_EH_PE_absptr => {
+                self.write_udata(val, size)
+            }
+            Address::Symbol { symbol, addend }
+                if eh_pe.application() == gimli::DW_EH_PE_pcrel
+                    && eh_pe.format() == gimli::DW_EH_PE_sdata4 =>
+            {
+                let offset = self.len() as u32;
+                self.relocs.push(DebugReloc {
+                    offset,
+                    size: 4,
+                    name: DebugRelocName::Symbol(symbol),
+                    addend,
+                    kind: DebugRelocKind::PcRelative,
+                });
+                self.write_udata(0, 4)
+            }
+            _ => Err(gimli::write::Error::UnsupportedPointerEncoding(eh_pe)),
+        }
+    }
+
+    fn write_offset(&mut self, val: usize, section: SectionId, size: u8) -> Result<()> {
+        let offset = self.len() as u32;
+        self.relocs.push(DebugReloc {
+            offset,
+            size,
+            name: DebugRelocName::Section(section),
+            addend: val as i64,
+            kind: DebugRelocKind::Absolute,
+        });
+        self.write_udata(0, size)
+    }
+
+    fn write_offset_at(
+        &mut self,
+        offset: usize,
+        val: usize,
+        section: SectionId,
+        size: u8,
+    ) -> Result<()> {
+        self.relocs.push(DebugReloc {
+            offset: offset as u32,
+            size,
+            name: DebugRelocName::Section(section),
+            addend: val as i64,
+            kind: DebugRelocKind::Absolute,
+        });
+        self.write_udata_at(offset, 0, size)
+    }
+}
@@ -0,0 +1,219 @@
+//! Lexical blocks and inlined functions. Every MIR source scope containing code gets a
+//! `DW_TAG_lexical_block` covering the code of the scope and all of its children, except for the
+//! outermost scope of a function inlined by the MIR inliner, which gets a
+//! `DW_TAG_inlined_subroutine` instead.
+//!
+//! The MIR doesn't record which scopes were inlined. The inliner sets the span of these scopes to
+//! the callsite, but the statements copied into them keep the span of the callee. A scope is
+//! considered inlined when its code lies outside of the body of the function its parent scope
+//! belongs to.
+
+use rustc_index::vec::IndexVec;
+
+use syntax_pos::hygiene;
+
+use gimli::write::{Address, AttributeValue, Range, RangeList, UnitEntryId};
+
+use crate::prelude::*;
+
+use super::{line_program_add_file, DebugContext, FunctionDebugContext};
+
+impl<'a, 'tcx> FunctionDebugContext<'a, 'tcx> {
+    /// Add the entries for the source scopes of the function. `scope_ranges` contains the code
+    /// ranges directly belonging to every scope. Returns the entry for every scope, which is the
+    /// entry of the closest ancestor for scopes without code.
+    pub(super) fn define_scopes(
+        &mut self,
+        mut scope_ranges: IndexVec<SourceScope, Vec<(u32, u32)>>,
+    ) -> IndexVec<SourceScope, UnitEntryId> {
+        // Parent scopes always come before their children, so iterating in reverse adds the code
+        // of every child before it is added to the grandparent.
+        for (scope, scope_data) in self.mir.source_scopes.iter_enumerated().rev() {
+            if let Some(parent) = scope_data.parent_scope {
+                let child_ranges = scope_ranges[scope].clone();
+                scope_ranges[parent].extend(child_ranges);
+            }
+        }
+
+        let code_spans = self.scope_code_spans();
+
+        // The span of the body of the function the code of every scope belongs to.
+        let mut body_spans: IndexVec<SourceScope, Span> =
+            IndexVec::with_capacity(self.mir.source_scopes.len());
+        let mut scope_entries = IndexVec::with_capacity(self.mir.source_scopes.len());
+        for (scope, scope_data) in self.mir.source_scopes.iter_enumerated() {
+            let parent = match scope_data.parent_scope {
+                Some(parent) => parent,
+                None => {
+                    body_spans.push(self.mir.span);
+                    scope_entries.push(self.entry_id);
+                    continue;
+                }
+            };
+            let parent_id = scope_entries[parent];
+            let parent_body_span = body_spans[parent];
+
+            let inlined_callee = code_spans[scope].and_then(|code_span| {
+                let code_span = hygiene::walk_chain(code_span, parent_body_span.ctxt());
+                if parent_body_span.contains(code_span) {
+                    None
+                } else {
+                    self.debug_context.inlined_callee(code_span)
+                }
+            });
+            body_spans.push(match inlined_callee {
+                Some((_, callee_body_span)) => callee_body_span,
+                None => parent_body_span,
+            });
+
+            let ranges = &mut scope_ranges[scope];
+            if ranges.is_empty() {
+                scope_entries.push(parent_id);
+                continue;
+            }
+            ranges.sort();
+            let mut merged_ranges: Vec<(u32, u32)> = Vec::new();
+            for &(start, end) in ranges.iter() {
+                match merged_ranges.last_mut() {
+                    Some(last) if last.1 >= start => last.1 = last.1.max(end),
+                    _ => merged_ranges.push((start, end)),
+                }
+            }
+
+            let symbol = self.symbol;
+            let range_list = RangeList(
+                merged_ranges
+                    .into_iter()
+                    .map(|(start, end)| Range::StartEnd {
+                        begin: Address::Symbol {
+                            symbol,
+                            addend: i64::from(start),
+                        },
+                        end: Address::Symbol {
+                            symbol,
+                            addend: i64::from(end),
+                        },
+                    })
+                    .collect(),
+            );
+
+            let scope_id = match inlined_callee {
+                Some((callee, _)) => {
+                    let callsite = hygiene::walk_chain(scope_data.span, parent_body_span.ctxt());
+                    let callsite = if parent_body_span.contains(callsite) {
+                        Some(callsite)
+                    } else {
+                        None
+                    };
+                    self.debug_context
+                        .add_inlined_subroutine(parent_id, callee, callsite)
+                }
+                None => self
+                    .debug_context
+                    .dwarf
+                    .unit
+                    .add(parent_id, gimli::DW_TAG_lexical_block),
+            };
+            let unit = &mut self.debug_context.dwarf.unit;
+            let range_list_id = unit.ranges.add(range_list);
+            unit.get_mut(scope_id).set(
+                gimli::DW_AT_ranges,
+                AttributeValue::RangeListRef(range_list_id),
+            );
+            scope_entries.push(scope_id);
+        }
+        scope_entries
+    }
+
+    /// The span of the first statement or terminator directly belonging to every scope.
+    fn scope_code_spans(&self) -> IndexVec<SourceScope, Option<Span>> {
+        let mut code_spans = IndexVec::from_elem(None, &self.mir.source_scopes);
+        for bb_data in self.mir.basic_blocks() {
+            let source_infos = bb_data
+                .statements
+                .iter()
+                .map(|stmt| &stmt.source_info)
+                .chain(std::iter::once(&bb_data.terminator().source_info));
+            for source_info in source_infos {
+                code_spans[source_info.scope].get_or_insert(source_info.span);
+            }
+        }
+        code_spans
+    }
+}
+
+impl<'tcx> DebugContext<'tcx> {
+    /// The function of the local crate whose body contains `span` together with the span of that
+    /// body. The HIR of other crates isn't available, so code inlined from them isn't found.
+    fn inlined_callee(&self, span: Span) -> Option<(DefId, Span)> {
+        let hir = self.tcx.hir();
+        hir.krate()
+            .body_ids
+            .iter()
+            .map(|&body_id| (body_id, hir.body(body_id).value.span))
+            .filter(|&(_, body_span)| body_span.contains(span))
+            // Closures are nested in the body of their parent.
+            .min_by_key(|&(_, body_span)| body_span.hi() - body_span.lo())
+            .map(|(body_id, body_span)| (hir.body_owner_def_id(body_id), body_span))
+    }
+
+    /// Add a `DW_TAG_inlined_subroutine` for a copy of `callee` inlined at `callsite`.
+    fn add_inlined_subroutine(
+        &mut self,
+        parent_id: UnitEntryId,
+        callee: DefId,
+        callsite: Option<Span>,
+    ) -> UnitEntryId {
+        let origin_id = self.abstract_subprogram(callee);
+        let entry_id = self.dwarf.unit.add(parent_id, gimli::DW_TAG_inlined_subroutine);
+        let call_location = callsite.map(|callsite| {
+            let source_map = self.tcx.sess.source_map();
+            let loc = source_map.lookup_char_pos(callsite.lo());
+            let file_id = line_program_add_file(
+                &mut self.dwarf.unit.line_program,
+                &mut self.dwarf.line_strings,
+                source_map.path_mapping(),
+                &loc.file.name,
+            );
+            (file_id, loc.line as u64)
+        });
+
+        let entry = self.dwarf.unit.get_mut(entry_id);
+        entry.set(
+            gimli::DW_AT_abstract_origin,
+            AttributeValue::ThisUnitEntryRef(origin_id),
+        );
+        if let Some((file_id, line)) = call_location {
+            entry.set(
+                gimli::DW_AT_call_file,
+                AttributeValue::FileIndex(Some(file_id)),
+            );
+            entry.set(gimli::DW_AT_call_line, AttributeValue::Udata(line));
+        }
+        entry_id
+    }
+
+    /// The abstract `DW_TAG_subprogram` which the inlined copies of `def_id` refer to. It has no
+    /// code of its own.
+    fn abstract_subprogram(&mut self, def_id: DefId) -> UnitEntryId {
+        if let Some(&entry_id) = self.abstract_subprograms.get(&def_id) {
+            return entry_id;
+        }
+
+        let tcx = self.tcx;
+        let scope = match tcx.parent(def_id) {
+            Some(parent) => self.item_namespace(parent),
+            None => self.dwarf.unit.root(),
+        };
+        let name = tcx.def_key(def_id).disambiguated_data.data.to_string();
+        let entry_id = self.new_named_entry(scope, gimli::DW_TAG_subprogram, &name);
+        self.dwarf.unit.get_mut(entry_id).set(
+            gimli::DW_AT_inline,
+            AttributeValue::Inline(gimli::DW_INL_inlined),
+        );
+        self.emit_location(tcx, entry_id, tcx.def_span(def_id));
+
+        self.abstract_subprograms.insert(def_id, entry_id);
+        entry_id
+    }
+}
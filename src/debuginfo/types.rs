@@ -0,0 +1,397 @@
+//! Type information. Every `Ty` is emitted at most once per unit and referenced from then on. The
+//! entries have the same shape as the ones emitted by the LLVM backend of rustc, so the Rust
+//! support of debuggers works with them.
+
+use rustc::ty::layout::{DiscriminantKind, LayoutCx, Variants};
+use rustc::ty::DefIdTree;
+use rustc_codegen_ssa::debuginfo::type_names::compute_debuginfo_type_name;
+
+use gimli::write::{AttributeValue, UnitEntryId};
+
+use crate::prelude::*;
+
+use super::DebugContext;
+
+impl<'tcx> DebugContext<'tcx> {
+    /// Get the entry describing `ty`, emitting it first when necessary.
+    pub(super) fn dwarf_ty(&mut self, ty: Ty<'tcx>) -> UnitEntryId {
+        if let Some(&type_id) = self.types.get(&ty) {
+            return type_id;
+        }
+
+        let layout = self.layout_of(ty);
+        match ty.kind {
+            ty::Bool => self.base_type(layout, gimli::DW_ATE_boolean),
+            ty::Char => self.base_type(layout, gimli::DW_ATE_UTF),
+            ty::Int(_) => self.base_type(layout, gimli::DW_ATE_signed),
+            ty::Uint(_) => self.base_type(layout, gimli::DW_ATE_unsigned),
+            ty::Float(_) => self.base_type(layout, gimli::DW_ATE_float),
+            ty::Never => self.base_type(layout, gimli::DW_ATE_unsigned),
+            ty::Ref(_, pointee_ty, _) | ty::RawPtr(TypeAndMut { ty: pointee_ty, .. }) => {
+                self.pointer_type(layout, pointee_ty)
+            }
+            ty::Adt(adt_def, _) if adt_def.is_box() => self.pointer_type(layout, ty.boxed_ty()),
+            ty::Array(elem_ty, len) => {
+                let type_id = self.new_type(layout, gimli::DW_TAG_array_type);
+                let elem_type_id = self.dwarf_ty(elem_ty);
+                let type_entry = self.dwarf.unit.get_mut(type_id);
+                type_entry.set(
+                    gimli::DW_AT_type,
+                    AttributeValue::ThisUnitEntryRef(elem_type_id),
+                );
+
+                let subrange_id = self.dwarf.unit.add(type_id, gimli::DW_TAG_subrange_type);
+                let subrange_entry = self.dwarf.unit.get_mut(subrange_id);
+                subrange_entry.set(
+                    gimli::DW_AT_count,
+                    AttributeValue::Udata(len.eval_usize(self.tcx, ParamEnv::reveal_all())),
+                );
+
+                type_id
+            }
+            ty::Tuple(_) | ty::Closure(..) => {
+                let type_id = self.new_type(layout, gimli::DW_TAG_structure_type);
+                self.add_fields(type_id, layout, |i| format!("__{}", i));
+                type_id
+            }
+            ty::Adt(adt_def, _) if adt_def.is_enum() => self.enum_type(layout, adt_def),
+            ty::Adt(adt_def, _) => {
+                let tag = if adt_def.is_union() {
+                    gimli::DW_TAG_union_type
+                } else {
+                    gimli::DW_TAG_structure_type
+                };
+                let type_id = self.new_type(layout, tag);
+                let variant = adt_def.non_enum_variant();
+                self.add_fields(type_id, layout, |i| variant.fields[i].ident.to_string());
+                type_id
+            }
+            ty::FnPtr(fn_sig) => {
+                let type_id = self.new_type(layout, gimli::DW_TAG_pointer_type);
+                let subroutine_id = self.subroutine_type(fn_sig);
+                let type_entry = self.dwarf.unit.get_mut(type_id);
+                type_entry.set(
+                    gimli::DW_AT_type,
+                    AttributeValue::ThisUnitEntryRef(subroutine_id),
+                );
+                type_id
+            }
+            // FIXME describe generators and trait objects
+            _ => self.new_type(layout, gimli::DW_TAG_structure_type),
+        }
+    }
+
+    fn layout_of(&self, ty: Ty<'tcx>) -> TyLayout<'tcx> {
+        self.tcx
+            .layout_of(ParamEnv::reveal_all().and(ty))
+            .unwrap_or_else(|e| bug!("failed to get layout for {}: {}", ty, e))
+    }
+
+    fn layout_cx(&self) -> LayoutCx<'tcx, TyCtxt<'tcx>> {
+        LayoutCx {
+            tcx: self.tcx,
+            param_env: ParamEnv::reveal_all(),
+        }
+    }
+
+    /// Add a named entry with the size of `layout` and remember it as the entry for `layout.ty`.
+    /// It is remembered before any children are added, so recursive types refer back to it
+    /// instead of recursing forever.
+    ///
+    /// Like the LLVM backend, ADTs and closures are nested in the namespace of their parent item
+    /// and use their unqualified name, while all other types are added to the root of the unit
+    /// with a fully qualified name, eg. `&[alloc::string::String]`.
+    fn new_type(&mut self, layout: TyLayout<'tcx>, tag: gimli::DwTag) -> UnitEntryId {
+        let tcx = self.tcx;
+        let parent = match layout.ty.kind {
+            ty::Adt(adt_def, _) => tcx.parent(adt_def.did),
+            ty::Closure(def_id, _) => tcx.parent(def_id),
+            _ => None,
+        };
+        let (scope, name) = match parent {
+            Some(parent) => (
+                self.item_namespace(parent),
+                compute_debuginfo_type_name(tcx, layout.ty, false),
+            ),
+            None => (
+                self.dwarf.unit.root(),
+                compute_debuginfo_type_name(tcx, layout.ty, true),
+            ),
+        };
+
+        let type_id = self.new_named_entry(scope, tag, &name);
+        let type_entry = self.dwarf.unit.get_mut(type_id);
+        type_entry.set(
+            gimli::DW_AT_byte_size,
+            AttributeValue::Udata(layout.size.bytes()),
+        );
+        self.types.insert(layout.ty, type_id);
+        type_id
+    }
+
+    pub(super) fn new_named_entry(
+        &mut self,
+        parent: UnitEntryId,
+        tag: gimli::DwTag,
+        name: &str,
+    ) -> UnitEntryId {
+        let entry_id = self.dwarf.unit.add(parent, tag);
+        let name_id = self.dwarf.strings.add(name);
+        let entry = self.dwarf.unit.get_mut(entry_id);
+        entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name_id));
+        entry_id
+    }
+
+    /// The `DW_TAG_subroutine_type` a function pointer points to. Like for subprograms, the
+    /// return type is omitted for functions returning `()`.
+    fn subroutine_type(&mut self, fn_sig: PolyFnSig<'tcx>) -> UnitEntryId {
+        let fn_sig = self
+            .tcx
+            .normalize_erasing_late_bound_regions(ParamEnv::reveal_all(), &fn_sig);
+
+        let subroutine_id = self
+            .dwarf
+            .unit
+            .add(self.dwarf.unit.root(), gimli::DW_TAG_subroutine_type);
+        if !fn_sig.output().is_unit() {
+            let return_type_id = self.dwarf_ty(fn_sig.output());
+            let subroutine_entry = self.dwarf.unit.get_mut(subroutine_id);
+            subroutine_entry.set(
+                gimli::DW_AT_type,
+                AttributeValue::ThisUnitEntryRef(return_type_id),
+            );
+        }
+        for &input_ty in fn_sig.inputs() {
+            let param_type_id = self.dwarf_ty(input_ty);
+            let param_id = self
+                .dwarf
+                .unit
+                .add(subroutine_id, gimli::DW_TAG_formal_parameter);
+            let param_entry = self.dwarf.unit.get_mut(param_id);
+            param_entry.set(
+                gimli::DW_AT_type,
+                AttributeValue::ThisUnitEntryRef(param_type_id),
+            );
+        }
+        if fn_sig.c_variadic {
+            self.dwarf
+                .unit
+                .add(subroutine_id, gimli::DW_TAG_unspecified_parameters);
+        }
+        subroutine_id
+    }
+
+    fn base_type(&mut self, layout: TyLayout<'tcx>, encoding: gimli::DwAte) -> UnitEntryId {
+        let type_id = self.new_type(layout, gimli::DW_TAG_base_type);
+        let type_entry = self.dwarf.unit.get_mut(type_id);
+        type_entry.set(gimli::DW_AT_encoding, AttributeValue::Encoding(encoding));
+        type_id
+    }
+
+    /// Thin pointers become `DW_TAG_pointer_type`. Fat pointers become a struct containing the
+    /// data pointer and the metadata, like `&[T]` with `data_ptr` and `length`.
+    fn pointer_type(&mut self, layout: TyLayout<'tcx>, pointee_ty: Ty<'tcx>) -> UnitEntryId {
+        let tcx = self.tcx;
+
+        if pointee_ty.is_sized(tcx.at(DUMMY_SP), ParamEnv::reveal_all()) {
+            let type_id = self.new_type(layout, gimli::DW_TAG_pointer_type);
+            let pointee_type_id = self.dwarf_ty(pointee_ty);
+            let type_entry = self.dwarf.unit.get_mut(type_id);
+            type_entry.set(
+                gimli::DW_AT_type,
+                AttributeValue::ThisUnitEntryRef(pointee_type_id),
+            );
+            return type_id;
+        }
+
+        let type_id = self.new_type(layout, gimli::DW_TAG_structure_type);
+        let (data_ptr_ty, metadata_name, metadata_ty) = match pointee_ty.kind {
+            ty::Str => (tcx.mk_imm_ptr(tcx.types.u8), "length", tcx.types.usize),
+            ty::Slice(elem_ty) => (tcx.mk_imm_ptr(elem_ty), "length", tcx.types.usize),
+            ty::Dynamic(..) => (
+                tcx.mk_mut_ptr(tcx.types.u8),
+                "vtable",
+                tcx.mk_imm_ref(
+                    tcx.lifetimes.re_static,
+                    tcx.mk_array(tcx.types.usize, 3),
+                ),
+            ),
+            // Structs with an unsized field
+            _ => (tcx.mk_mut_ptr(tcx.types.u8), "extra", tcx.types.usize),
+        };
+        let data_ptr_name = if let ty::Dynamic(..) = pointee_ty.kind {
+            "pointer"
+        } else {
+            "data_ptr"
+        };
+        let pointer_size = tcx.data_layout.pointer_size.bytes();
+        self.add_member(type_id, data_ptr_name, data_ptr_ty, 0);
+        self.add_member(type_id, metadata_name, metadata_ty, pointer_size);
+
+        type_id
+    }
+
+    fn add_member(&mut self, parent: UnitEntryId, name: &str, ty: Ty<'tcx>, offset: u64) {
+        let member_type_id = self.dwarf_ty(ty);
+        let member_id = self.new_named_entry(parent, gimli::DW_TAG_member, name);
+        let member_entry = self.dwarf.unit.get_mut(member_id);
+        member_entry.set(
+            gimli::DW_AT_type,
+            AttributeValue::ThisUnitEntryRef(member_type_id),
+        );
+        member_entry.set(
+            gimli::DW_AT_data_member_location,
+            AttributeValue::Udata(offset),
+        );
+    }
+
+    fn add_fields(
+        &mut self,
+        parent: UnitEntryId,
+        layout: TyLayout<'tcx>,
+        field_name: impl Fn(usize) -> String,
+    ) {
+        for i in 0..layout.fields.count() {
+            let field_layout = layout.field(&self.layout_cx(), i).unwrap();
+            self.add_member(
+                parent,
+                &field_name(i),
+                field_layout.ty,
+                layout.fields.offset(i).bytes(),
+            );
+        }
+    }
+
+    /// Fieldless enums become `DW_TAG_enumeration_type`. All other enums become a struct with a
+    /// `DW_TAG_variant_part` selecting between one struct per variant based on the tag or niche.
+    fn enum_type(&mut self, layout: TyLayout<'tcx>, adt_def: &'tcx ty::AdtDef) -> UnitEntryId {
+        let tcx = self.tcx;
+
+        let (discr_index, discr_kind) = match layout.variants {
+            Variants::Single { index } => {
+                let type_id = self.new_type(layout, gimli::DW_TAG_structure_type);
+                if let Some(variant) = adt_def.variants.get(index) {
+                    let variant_layout = layout.for_variant(&self.layout_cx(), index);
+                    self.add_fields(type_id, variant_layout, |i| {
+                        variant.fields[i].ident.to_string()
+                    });
+                }
+                return type_id;
+            }
+            Variants::Multiple {
+                discr: _,
+                discr_index,
+                ref discr_kind,
+                variants: _,
+            } => (discr_index, discr_kind),
+        };
+
+        let discr_layout = layout.field(&self.layout_cx(), discr_index).unwrap();
+        let discr_offset = layout.fields.offset(discr_index).bytes();
+        let discr_value = |variant_index: VariantIdx| -> Option<u64> {
+            let value = match *discr_kind {
+                DiscriminantKind::Tag => {
+                    layout
+                        .ty
+                        .discriminant_for_variant(tcx, variant_index)
+                        .unwrap()
+                        .val
+                }
+                DiscriminantKind::Niche {
+                    dataful_variant,
+                    ref niche_variants,
+                    niche_start,
+                } => {
+                    if variant_index == dataful_variant {
+                        return None;
+                    }
+                    let niche_value = variant_index.as_u32() - niche_variants.start().as_u32();
+                    u128::from(niche_value).wrapping_add(niche_start)
+                }
+            };
+            let bits = discr_layout.size.bits();
+            let value = if bits < 128 {
+                value & ((1 << bits) - 1)
+            } else {
+                value
+            };
+            Some(value as u64)
+        };
+
+        if adt_def.variants.iter().all(|variant| variant.fields.is_empty()) {
+            let type_id = self.new_type(layout, gimli::DW_TAG_enumeration_type);
+            let discr_type_id = self.dwarf_ty(discr_layout.ty);
+            let type_entry = self.dwarf.unit.get_mut(type_id);
+            type_entry.set(
+                gimli::DW_AT_type,
+                AttributeValue::ThisUnitEntryRef(discr_type_id),
+            );
+            for (variant_index, variant) in adt_def.variants.iter_enumerated() {
+                let enumerator_id = self.new_named_entry(
+                    type_id,
+                    gimli::DW_TAG_enumerator,
+                    &variant.ident.to_string(),
+                );
+                let enumerator_entry = self.dwarf.unit.get_mut(enumerator_id);
+                enumerator_entry.set(
+                    gimli::DW_AT_const_value,
+                    AttributeValue::Udata(discr_value(variant_index).unwrap()),
+                );
+            }
+            return type_id;
+        }
+
+        let type_id = self.new_type(layout, gimli::DW_TAG_structure_type);
+        let variant_part_id = self.dwarf.unit.add(type_id, gimli::DW_TAG_variant_part);
+
+        let discr_type_id = self.dwarf_ty(discr_layout.ty);
+        let discr_member_id = self.dwarf.unit.add(variant_part_id, gimli::DW_TAG_member);
+        let discr_member_entry = self.dwarf.unit.get_mut(discr_member_id);
+        discr_member_entry.set(
+            gimli::DW_AT_type,
+            AttributeValue::ThisUnitEntryRef(discr_type_id),
+        );
+        discr_member_entry.set(
+            gimli::DW_AT_data_member_location,
+            AttributeValue::Udata(discr_offset),
+        );
+        discr_member_entry.set(gimli::DW_AT_artificial, AttributeValue::Flag(true));
+        let variant_part_entry = self.dwarf.unit.get_mut(variant_part_id);
+        variant_part_entry.set(
+            gimli::DW_AT_discr,
+            AttributeValue::ThisUnitEntryRef(discr_member_id),
+        );
+
+        for (variant_index, variant) in adt_def.variants.iter_enumerated() {
+            let variant_name = variant.ident.to_string();
+
+            // The struct describing the fields of the variant is nested in the enum type.
+            let variant_struct_id =
+                self.new_named_entry(type_id, gimli::DW_TAG_structure_type, &variant_name);
+            let variant_layout = layout.for_variant(&self.layout_cx(), variant_index);
+            let variant_struct_entry = self.dwarf.unit.get_mut(variant_struct_id);
+            variant_struct_entry.set(
+                gimli::DW_AT_byte_size,
+                AttributeValue::Udata(layout.size.bytes()),
+            );
+            self.add_fields(variant_struct_id, variant_layout, |i| {
+                variant.fields[i].ident.to_string()
+            });
+
+            let variant_id = self.dwarf.unit.add(variant_part_id, gimli::DW_TAG_variant);
+            if let Some(value) = discr_value(variant_index) {
+                let variant_entry = self.dwarf.unit.get_mut(variant_id);
+                variant_entry.set(gimli::DW_AT_discr_value, AttributeValue::Udata(value));
+            }
+            let member_id = self.new_named_entry(variant_id, gimli::DW_TAG_member, &variant_name);
+            let member_entry = self.dwarf.unit.get_mut(member_id);
+            member_entry.set(
+                gimli::DW_AT_type,
+                AttributeValue::ThisUnitEntryRef(variant_struct_id),
+            );
+            member_entry.set(gimli::DW_AT_data_member_location, AttributeValue::Udata(0));
+        }
+
+        type_id
+    }
+}
@@ -0,0 +1,211 @@
+//! Call frame information in `.eh_frame`, so debuggers, profilers and backtraces can walk through
+//! the frames of functions compiled by Cranelift. It is emitted independently of debuginfo.
+
+use cranelift::codegen::ir::{ArgumentLoc, ArgumentPurpose, InstructionData, Opcode, ValueDef};
+use cranelift::codegen::isa::TargetIsa;
+
+use gimli::write::{
+    Address, CallFrameInstruction, CieId, CommonInformationEntry, EhFrame, FrameDescriptionEntry,
+    FrameTable,
+};
+use gimli::{Encoding, Format, Register, RunTimeEndian};
+
+use faerie::*;
+
+use crate::prelude::*;
+
+use super::{
+    dwarf_register, is_x86_64, target_endian, WriterRelocate, X86_64_RA, X86_64_RBP, X86_64_RSP,
+};
+
+pub struct UnwindContext {
+    endian: RunTimeEndian,
+    symbols: indexmap::IndexSet<String>,
+    frame_table: FrameTable,
+    cie_id: CieId,
+}
+
+impl UnwindContext {
+    /// Only x86_64 ELF is supported for now, as the frame layout is derived from the prologue and
+    /// epilogue Cranelift inserts for x86_64 and Mach-O names the section differently.
+    pub fn new(tcx: TyCtxt<'_>, isa: &dyn TargetIsa) -> Option<Self> {
+        if !is_x86_64(isa) || isa.triple().binary_format != target_lexicon::BinaryFormat::Elf {
+            return None;
+        }
+
+        let encoding = Encoding {
+            format: Format::Dwarf32,
+            version: 1,
+            address_size: isa.pointer_bytes(),
+        };
+        let mut cie = CommonInformationEntry::new(encoding, 1, -8, Register(X86_64_RA));
+        cie.fde_address_encoding =
+            gimli::DwEhPe(gimli::DW_EH_PE_pcrel.0 | gimli::DW_EH_PE_sdata4.0);
+        cie.add_instruction(CallFrameInstruction::Cfa(Register(X86_64_RSP), 8));
+        cie.add_instruction(CallFrameInstruction::Offset(Register(X86_64_RA), -8));
+
+        let mut frame_table = FrameTable::default();
+        let cie_id = frame_table.add_cie(cie);
+
+        Some(UnwindContext {
+            endian: target_endian(tcx),
+            symbols: indexmap::IndexSet::new(),
+            frame_table,
+            cie_id,
+        })
+    }
+
+    /// Add an FDE for the function `name`, which has just been compiled in `context`.
+    ///
+    /// Cranelift doesn't describe the frames it lays out with unwind info, but the prologue and
+    /// epilogues inserted by `prologue_epilogue` are recorded in the function itself. The saved
+    /// registers are the `FramePointer` and `CalleeSaved` parameters, which the prologue pushes
+    /// in order at the start of the entry ebb, and the returns with the same purposes are the
+    /// values the epilogues pop. The push of the frame pointer is followed by setting it up, so
+    /// the CFA is `rbp + 16` from there until an epilogue pops `rbp` again.
+    pub fn add_function(&mut self, name: &str, context: &Context, isa: &dyn TargetIsa) {
+        let func = &context.func;
+        let entry_ebb = match func.layout.entry_block() {
+            Some(entry_ebb) => entry_ebb,
+            None => return,
+        };
+
+        let saved_regs = func
+            .signature
+            .params
+            .iter()
+            .zip(func.dfg.ebb_params(entry_ebb))
+            .filter(|(param, _)| is_saved_reg(param.purpose))
+            .map(|(param, &value)| match param.location {
+                ArgumentLoc::Reg(reg) => (value, dwarf_register(isa, reg)),
+                _ => (value, None),
+            })
+            .collect::<Vec<_>>();
+        // Only frames laid out by `prologue_epilogue` for System V start by saving `rbp`.
+        match saved_regs.first() {
+            Some(&(_, Some(X86_64_RBP))) => {}
+            _ => return,
+        }
+
+        let encinfo = isa.encoding_info();
+        let mut ebbs = func.layout.ebbs().collect::<Vec<_>>();
+        ebbs.sort_by_key(|ebb| func.offsets[*ebb]); // Ensure inst offsets always increase
+
+        // The instructions popping `rbp` in the epilogues.
+        let mut frame_pointer_pops = FxHashSet::default();
+        for &ebb in &ebbs {
+            if let Some(inst) = func.layout.last_inst(ebb) {
+                if func.dfg[inst].opcode().is_return() {
+                    let frame_pointer = func
+                        .signature
+                        .returns
+                        .iter()
+                        .zip(func.dfg.inst_args(inst))
+                        .find(|(ret, _)| ret.purpose == ArgumentPurpose::FramePointer);
+                    if let Some((_, &value)) = frame_pointer {
+                        if let ValueDef::Result(pop, _) = func.dfg.value_def(value) {
+                            frame_pointer_pops.insert(pop);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut instructions = Vec::new();
+        let mut sets_up_frame_pointer = false;
+        let mut in_epilogue = false;
+        let mut end = 0;
+        for ebb in ebbs {
+            for (offset, inst, size) in func.inst_offsets(ebb, &encinfo) {
+                let after = offset + size;
+                end = after;
+                if sets_up_frame_pointer {
+                    instructions.push((
+                        after,
+                        CallFrameInstruction::CfaRegister(Register(X86_64_RBP)),
+                    ));
+                    sets_up_frame_pointer = false;
+                }
+                match func.dfg[inst] {
+                    InstructionData::Unary {
+                        opcode: Opcode::X86Push,
+                        arg,
+                    } if ebb == entry_ebb => {
+                        let index = match saved_regs.iter().position(|&(value, _)| value == arg) {
+                            Some(index) => index,
+                            None => continue,
+                        };
+                        if index == 0 {
+                            instructions.push((after, CallFrameInstruction::CfaOffset(16)));
+                            sets_up_frame_pointer = true;
+                        }
+                        if let (_, Some(reg)) = saved_regs[index] {
+                            instructions.push((
+                                after,
+                                CallFrameInstruction::Offset(Register(reg), -16 - 8 * index as i32),
+                            ));
+                        }
+                    }
+                    _ if frame_pointer_pops.contains(&inst) => {
+                        instructions.push((after, CallFrameInstruction::RememberState));
+                        instructions.push((
+                            after,
+                            CallFrameInstruction::Cfa(Register(X86_64_RSP), 8),
+                        ));
+                        in_epilogue = true;
+                    }
+                    // Code after the return of an epilogue is inside of the frame again.
+                    ref inst_data if in_epilogue && inst_data.opcode().is_return() => {
+                        instructions.push((after, CallFrameInstruction::RestoreState));
+                        in_epilogue = false;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let (symbol, _) = self.symbols.insert_full(name.to_string());
+        let mut fde = FrameDescriptionEntry::new(Address::Symbol { symbol, addend: 0 }, end);
+        for (offset, instruction) in instructions {
+            if offset < end {
+                fde.add_instruction(offset, instruction);
+            }
+        }
+        self.frame_table.add_fde(self.cie_id, fde);
+    }
+
+    /// Returns the name of the section, as it needs the `SHF_ALLOC` flag. See `elf::ElfFixups`.
+    pub fn emit(self, artifact: &mut Artifact) -> Vec<String> {
+        let mut eh_frame = EhFrame(WriterRelocate::new(self.endian));
+        self.frame_table.write_eh_frame(&mut eh_frame).unwrap();
+        let WriterRelocate {
+            mut writer, relocs, ..
+        } = eh_frame.0;
+
+        if writer.slice().is_empty() {
+            return Vec::new();
+        }
+
+        artifact
+            .declare_with(".eh_frame", Decl::section(SectionKind::Text), writer.take())
+            .unwrap();
+        for reloc in &relocs {
+            artifact
+                .link_with(
+                    faerie::Link {
+                        from: ".eh_frame",
+                        to: reloc.name(&self.symbols),
+                        at: u64::from(reloc.offset),
+                    },
+                    reloc.faerie_reloc(),
+                )
+                .expect("faerie relocation error");
+        }
+
+        vec![".eh_frame".to_string()]
+    }
+}
+
+fn is_saved_reg(purpose: ArgumentPurpose) -> bool {
+    purpose == ArgumentPurpose::FramePointer || purpose == ArgumentPurpose::CalleeSaved
+}
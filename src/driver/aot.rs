@@ -0,0 +1,611 @@
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+
+use rustc::dep_graph::cgu_reuse_tracker::CguReuse;
+use rustc::dep_graph::{WorkProduct, WorkProductFileKind, WorkProductId};
+use rustc::ich::StableHashingContext;
+use rustc::middle::cstore::EncodedMetadata;
+use rustc::mir::mono::{CodegenUnit, Linkage as RLinkage, Visibility};
+use rustc::session::config::{DebugInfo, OutputType};
+use rustc_codegen_ssa::back::linker::LinkerInfo;
+use rustc_codegen_ssa::CrateInfo;
+use rustc_data_structures::stable_hasher::{HashStable, StableHasher};
+use syntax::symbol::InternedString;
+
+use cranelift::codegen::settings;
+use cranelift_faerie::*;
+
+use crate::prelude::*;
+use crate::elf::ElfFixups;
+
+fn new_module(tcx: TyCtxt<'_>, flags: &settings::Flags, name: String) -> Module<FaerieBackend> {
+    new_module_with_features(tcx, flags, name, &[])
+}
+
+/// `flags` are the isa independent settings, which `run_aot` resolves once for all modules. Every
+/// `Module` takes ownership of its isa, so only the isa itself is built per module. It can't be
+/// shared between the modules of a feature set either: a forwarding `TargetIsa` implementation
+/// isn't possible, as some of its methods use types which `cranelift_codegen` doesn't export.
+fn new_module_with_features(
+    tcx: TyCtxt<'_>,
+    flags: &settings::Flags,
+    name: String,
+    isa_flags: &[&str],
+) -> Module<FaerieBackend> {
+    let module: Module<FaerieBackend> = Module::new(
+        FaerieBuilder::new(
+            crate::build_isa_from_flags(tcx.sess, flags.clone(), isa_flags),
+            name + ".o",
+            FaerieTrapCollection::Disabled,
+            cranelift_module::default_libcall_names(),
+        )
+        .unwrap(),
+    );
+    assert_eq!(pointer_ty(tcx), module.target_config().pointer_type());
+    module
+}
+
+struct ModuleCodegenResult(Vec<CompiledModule>, Option<(WorkProductId, WorkProduct)>);
+
+impl HashStable<StableHashingContext<'_>> for ModuleCodegenResult {
+    fn hash_stable(&self, _: &mut StableHashingContext<'_>, _: &mut StableHasher) {
+        // do nothing
+    }
+}
+
+/// An object file which is ready to be written, see `ObjectWriter`.
+struct ObjectJob {
+    artifact: faerie::Artifact,
+    elf_fixups: Option<ElfFixups>,
+    path: PathBuf,
+}
+
+impl ObjectJob {
+    fn write(self) -> Result<(), String> {
+        let mut obj = self
+            .artifact
+            .emit()
+            .map_err(|err| format!("error emitting object file `{}`: {}", self.path.display(), err))?;
+        if let Some(elf_fixups) = self.elf_fixups {
+            elf_fixups.apply(&mut obj);
+        }
+        std::fs::write(&self.path, obj)
+            .map_err(|err| format!("error writing object file `{}`: {}", self.path.display(), err))
+    }
+}
+
+/// Serializes and writes object files on a pool of worker threads, while the main thread
+/// translates the next codegen unit.
+///
+/// Only the translation itself has to happen on the main thread, as it needs the `TyCtxt`, which
+/// can't be shared with other threads outside of the parallel compiler.
+struct ObjectWriter {
+    jobs: mpsc::Sender<ObjectJob>,
+    workers: Vec<std::thread::JoinHandle<Vec<String>>>,
+}
+
+impl ObjectWriter {
+    /// Start `threads` worker threads.
+    fn new(threads: usize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<ObjectJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let receiver = receiver.clone();
+                std::thread::Builder::new()
+                    .name(format!("cg_clif object writer {}", i))
+                    .spawn(move || {
+                        let mut errors = Vec::new();
+                        loop {
+                            // Release the lock before writing, so other workers can take a job.
+                            let job = receiver.lock().unwrap().recv();
+                            match job {
+                                Ok(job) => errors.extend(job.write().err()),
+                                Err(mpsc::RecvError) => return errors,
+                            }
+                        }
+                    })
+                    .unwrap()
+            })
+            .collect();
+        ObjectWriter { jobs, workers }
+    }
+
+    /// Queue `artifact` to be written as the object file of a module. The object file only exists
+    /// once `finish` returns.
+    fn write_module(
+        &self,
+        tcx: TyCtxt<'_>,
+        name: String,
+        kind: ModuleKind,
+        (artifact, elf_fixups): (faerie::Artifact, ElfFixups),
+    ) -> CompiledModule {
+        let tmp_file = tcx
+            .output_filenames(LOCAL_CRATE)
+            .temp_path(OutputType::Object, Some(&name));
+        self.jobs
+            .send(ObjectJob {
+                artifact,
+                elf_fixups: if tcx.sess.target.target.options.is_like_osx {
+                    None
+                } else {
+                    Some(elf_fixups)
+                },
+                path: tmp_file.clone(),
+            })
+            .unwrap();
+
+        CompiledModule {
+            name,
+            kind,
+            object: Some(tmp_file),
+            bytecode: None,
+            bytecode_compressed: None,
+        }
+    }
+
+    /// Wait for all object files to be written.
+    fn finish(self, sess: &Session) {
+        drop(self.jobs);
+        for worker in self.workers {
+            match worker.join() {
+                Ok(errors) => {
+                    for err in errors {
+                        sess.err(&err);
+                    }
+                }
+                Err(_) => sess.err("an object writer thread panicked"),
+            }
+        }
+        sess.abort_if_errors();
+    }
+}
+
+fn finish_module(
+    mut module: Module<FaerieBackend>,
+    debug: Option<DebugContext>,
+    unwind: Option<UnwindContext>,
+    hidden_symbols: FxHashSet<String>,
+) -> (faerie::Artifact, ElfFixups) {
+    module.finalize_definitions();
+    let mut artifact = module.finish().artifact;
+
+    let mut elf_fixups = ElfFixups {
+        hidden_symbols,
+        compressed_sections: Vec::new(),
+        alloc_sections: Vec::new(),
+    };
+
+    if let Some(mut debug) = debug {
+        elf_fixups.compressed_sections = debug.emit(&mut artifact);
+    }
+
+    if let Some(unwind) = unwind {
+        elf_fixups.alloc_sections = unwind.emit(&mut artifact);
+    }
+
+    (artifact, elf_fixups)
+}
+
+/// Copy the object files of a codegen unit to the incremental cache. A codegen unit containing
+/// `#[target_feature]` functions has an object file per set of isa flags, while
+/// `copy_cgu_workproducts_to_incr_comp_cache_dir` only supports a single one, so the files are
+/// saved under the names of their modules instead.
+fn save_work_product(
+    tcx: TyCtxt<'_>,
+    backend_config: &BackendConfig,
+    cgu: &CodegenUnit<'_>,
+    modules: &[CompiledModule],
+) -> Option<(WorkProductId, WorkProduct)> {
+    if tcx.sess.opts.incremental.is_none() || !backend_config.enable_incr_cache {
+        return None;
+    }
+
+    let mut saved_files = Vec::new();
+    for module in modules {
+        let object = module.object.as_ref().unwrap();
+        let file_name = format!("{}.o", module.name);
+        let path_in_incr_dir = rustc_incremental::in_incr_comp_dir_sess(tcx.sess, &file_name);
+        if let Err(err) = rustc_fs_util::link_or_copy(object, &path_in_incr_dir) {
+            tcx.sess.warn(&format!(
+                "error copying object file `{}` to incremental directory as `{}`: {}",
+                object.display(),
+                path_in_incr_dir.display(),
+                err
+            ));
+            return None;
+        }
+        saved_files.push((WorkProductFileKind::Object, file_name));
+    }
+
+    let work_product = WorkProduct {
+        cgu_name: cgu.name().to_string(),
+        saved_files,
+    };
+    Some((cgu.work_product_id(), work_product))
+}
+
+fn reuse_workproduct_for_cgu(
+    tcx: TyCtxt<'_>,
+    cgu: &CodegenUnit<'_>,
+) -> ModuleCodegenResult {
+    let incr_comp_session_dir = tcx.sess.incr_comp_session_dir();
+    let work_product = cgu.work_product(tcx);
+    let mut modules = Vec::new();
+    for (kind, saved_file) in &work_product.saved_files {
+        match kind {
+            WorkProductFileKind::Object => {}
+            WorkProductFileKind::Bytecode | WorkProductFileKind::BytecodeCompressed => {
+                bug!("cg_clif doesn't produce bytecode work products")
+            }
+        }
+
+        // Object files are saved under the name of their module, see `save_work_product`.
+        let name = Path::new(saved_file)
+            .file_stem()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let obj_out = tcx
+            .output_filenames(LOCAL_CRATE)
+            .temp_path(OutputType::Object, Some(&name));
+        let source_file = rustc_incremental::in_incr_comp_dir(&incr_comp_session_dir, &saved_file);
+        if let Err(err) = rustc_fs_util::link_or_copy(&source_file, &obj_out) {
+            tcx.sess.err(&format!(
+                "unable to copy {} to {}: {}",
+                source_file.display(),
+                obj_out.display(),
+                err
+            ));
+        }
+
+        modules.push(CompiledModule {
+            name,
+            kind: ModuleKind::Regular,
+            object: Some(obj_out),
+            bytecode: None,
+            bytecode_compressed: None,
+        });
+    }
+
+    ModuleCodegenResult(modules, Some((cgu.work_product_id(), work_product)))
+}
+
+fn new_debug_context<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    backend_config: &BackendConfig,
+    module: &Module<FaerieBackend>,
+) -> Option<DebugContext<'tcx>> {
+    if tcx.sess.opts.debuginfo != DebugInfo::None
+        // macOS debuginfo doesn't work yet (see #303)
+        && !tcx.sess.target.target.options.is_like_osx
+    {
+        let debug = DebugContext::new(
+            tcx,
+            module.target_config().pointer_type().bytes() as u8,
+            backend_config,
+        );
+        Some(debug)
+    } else {
+        None
+    }
+}
+
+/// Returns the object file of the codegen unit itself, followed by the object files for the
+/// functions which need different target features than the rest of the codegen unit. The work
+/// product is only saved by `run_aot` once `object_writer` has written the object files.
+fn module_codegen(
+    tcx: TyCtxt<'_>,
+    (cgu_name, backend_config, flags, object_writer): (
+        InternedString,
+        &BackendConfig,
+        &settings::Flags,
+        &ObjectWriter,
+    ),
+) -> ModuleCodegenResult {
+    let cgu = tcx.codegen_unit(cgu_name);
+    let (mono_items, feature_mono_items) =
+        split_by_target_features(tcx, cgu.items_in_deterministic_order(tcx));
+    let hidden_symbols = hidden_symbols(
+        tcx,
+        mono_items
+            .iter()
+            .chain(feature_mono_items.iter().flat_map(|(_, items)| items)),
+    );
+
+    let mut module = new_module(tcx, flags, cgu.name().as_str().to_string());
+    let mut debug = new_debug_context(tcx, backend_config, &module);
+    let mut unwind = UnwindContext::new(tcx, module.isa());
+
+    super::codegen_mono_items(
+        tcx,
+        &mut module,
+        backend_config,
+        debug.as_mut(),
+        unwind.as_mut(),
+        mono_items,
+    );
+    crate::main_shim::maybe_create_entry_wrapper(tcx, &mut module, Some(&*cgu));
+
+    let mut modules = vec![object_writer.write_module(
+        tcx,
+        cgu.name().as_str().to_string(),
+        ModuleKind::Regular,
+        finish_module(module, debug, unwind, hidden_symbols.clone()),
+    )];
+
+    // Cranelift uses a single isa for all functions of a `Module`, so every distinct set of isa
+    // flags gets its own module and object file.
+    for (isa_flags, mono_items) in feature_mono_items {
+        let name = format!("{}.{}", cgu.name(), isa_flags.join("."));
+        let mut module = new_module_with_features(tcx, flags, name.clone(), &isa_flags);
+        let mut debug = new_debug_context(tcx, backend_config, &module);
+        let mut unwind = UnwindContext::new(tcx, module.isa());
+
+        super::codegen_mono_items(
+            tcx,
+            &mut module,
+            backend_config,
+            debug.as_mut(),
+            unwind.as_mut(),
+            mono_items,
+        );
+
+        modules.push(object_writer.write_module(
+            tcx,
+            name,
+            ModuleKind::Regular,
+            finish_module(module, debug, unwind, hidden_symbols.clone()),
+        ));
+    }
+
+    ModuleCodegenResult(modules, None)
+}
+
+/// The symbol names of the functions which need hidden visibility.
+fn hidden_symbols<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'tcx>,
+    mono_items: impl Iterator<Item = &'a (MonoItem<'tcx>, (RLinkage, Visibility))>,
+) -> FxHashSet<String> {
+    mono_items
+        .filter_map(|&(mono_item, (_linkage, visibility))| match (mono_item, visibility) {
+            (MonoItem::Fn(instance), Visibility::Hidden) => {
+                Some(tcx.symbol_name(instance).name.as_str().to_string())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Split off all functions whose `#[target_feature]` attributes enable additional isa flags,
+/// grouped by those flags.
+///
+/// When a codegen unit is split, its items end up in different object files, so internal items
+/// are turned into hidden `linkonce_odr` items to keep references between them working. Their
+/// copies in other codegen units are identical, so the linker can keep any of them.
+fn split_by_target_features<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    mono_items: Vec<(MonoItem<'tcx>, (RLinkage, Visibility))>,
+) -> (
+    Vec<(MonoItem<'tcx>, (RLinkage, Visibility))>,
+    Vec<(Vec<&'static str>, Vec<(MonoItem<'tcx>, (RLinkage, Visibility))>)>,
+) {
+    let mono_items = mono_items
+        .into_iter()
+        .map(|item| {
+            let isa_flags = match item.0 {
+                MonoItem::Fn(instance) => crate::target_features::instance_isa_flags(tcx, instance),
+                MonoItem::Static(_) | MonoItem::GlobalAsm(_) => Vec::new(),
+            };
+            (isa_flags, item)
+        })
+        .collect::<Vec<_>>();
+    if mono_items.iter().all(|(isa_flags, _)| isa_flags.is_empty()) {
+        return (
+            mono_items.into_iter().map(|(_, item)| item).collect(),
+            Vec::new(),
+        );
+    }
+
+    let mut base_mono_items = Vec::new();
+    let mut feature_mono_items: Vec<(Vec<&'static str>, Vec<_>)> = Vec::new();
+    for (isa_flags, (mono_item, (linkage, visibility))) in mono_items {
+        let item = match linkage {
+            RLinkage::Internal => (mono_item, (RLinkage::LinkOnceODR, Visibility::Hidden)),
+            _ => (mono_item, (linkage, visibility)),
+        };
+        if isa_flags.is_empty() {
+            base_mono_items.push(item);
+            continue;
+        }
+        match feature_mono_items
+            .iter_mut()
+            .find(|(flags, _)| *flags == isa_flags)
+        {
+            Some((_, items)) => items.push(item),
+            None => feature_mono_items.push((isa_flags, vec![item])),
+        }
+    }
+
+    (base_mono_items, feature_mono_items)
+}
+
+pub fn run_aot(
+    tcx: TyCtxt<'_>,
+    metadata: EncodedMetadata,
+    need_metadata_module: bool,
+    backend_config: &BackendConfig,
+) -> Box<(CodegenResults, FxHashMap<WorkProductId, WorkProduct>)> {
+    let (_, cgus) = tcx.collect_and_partition_mono_items(LOCAL_CRATE);
+    let flags = crate::shared_flags(tcx.sess, backend_config, true);
+
+    // Marking the dep nodes of the codegen units green has to happen before any of them is
+    // codegened, so do it up front on the main thread.
+    let cgu_reuse = cgus
+        .iter()
+        .map(|cgu| {
+            let cgu_reuse = determine_cgu_reuse(tcx, backend_config, cgu);
+            tcx.sess
+                .cgu_reuse_tracker
+                .set_actual_reuse(&cgu.name().as_str(), cgu_reuse);
+            cgu_reuse
+        })
+        .collect::<Vec<_>>();
+    if tcx.sess.opts.debugging_opts.incremental_info {
+        let reused = cgu_reuse
+            .iter()
+            .filter(|&&cgu_reuse| cgu_reuse == CguReuse::PreLto)
+            .count();
+        println!(
+            "[incremental] Re-using {} out of {} codegen units",
+            reused,
+            cgus.len()
+        );
+    }
+
+    // Every codegen unit gets its own `Module` and object file. `TyCtxt` can't be shared with
+    // other threads outside of the parallel compiler and Cranelift compiles a function as part of
+    // defining it in its `Module`, so the codegen units are translated one after another. Only
+    // writing the object files is done in parallel.
+    let object_writer = ObjectWriter::new(std::cmp::min(tcx.sess.codegen_units(), cgus.len()));
+    let results = super::time("codegen mono items", || {
+        cgus.iter()
+            .zip(cgu_reuse)
+            .map(|(cgu, cgu_reuse)| {
+                match cgu_reuse {
+                    CguReuse::No => {}
+                    CguReuse::PreLto => return reuse_workproduct_for_cgu(tcx, &*cgu),
+                    CguReuse::PostLto => unreachable!(),
+                }
+
+                let dep_node = cgu.codegen_dep_node(tcx);
+                let (result, _) = tcx.dep_graph.with_task(
+                    dep_node,
+                    tcx,
+                    (cgu.name().clone(), backend_config, &flags, &object_writer),
+                    module_codegen,
+                    rustc::dep_graph::hash_result,
+                );
+                result
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut allocator_module = new_module(tcx, &flags, "allocator_shim".to_string());
+    let allocator_module = if crate::allocator::codegen(tcx, &mut allocator_module) {
+        Some(object_writer.write_module(
+            tcx,
+            "allocator_shim".to_string(),
+            ModuleKind::Allocator,
+            finish_module(allocator_module, None, None, FxHashSet::default()),
+        ))
+    } else {
+        None
+    };
+
+    object_writer.finish(tcx.sess);
+
+    let mut work_products = FxHashMap::default();
+    let mut modules = Vec::new();
+    for (cgu, ModuleCodegenResult(cgu_modules, work_product)) in cgus.iter().zip(results) {
+        let work_product =
+            work_product.or_else(|| save_work_product(tcx, backend_config, cgu, &cgu_modules));
+        if let Some((id, product)) = work_product {
+            work_products.insert(id, product);
+        }
+        modules.extend(cgu_modules);
+    }
+
+    // Keep the order of the modules independent of the partitioning order of the codegen units.
+    modules.sort_by(|a, b| a.name.cmp(&b.name));
+
+    tcx.sess.abort_if_errors();
+
+    rustc_incremental::assert_dep_graph(tcx);
+    rustc_incremental::save_dep_graph(tcx);
+    rustc_incremental::finalize_session_directory(tcx.sess, tcx.crate_hash(LOCAL_CRATE));
+
+    let metadata_module = if need_metadata_module {
+        let _timer = tcx.prof.generic_activity("codegen crate metadata");
+        let (metadata_cgu_name, tmp_file) = rustc::util::common::time(tcx.sess, "write compressed metadata", || {
+            use rustc::mir::mono::CodegenUnitNameBuilder;
+
+            let cgu_name_builder = &mut CodegenUnitNameBuilder::new(tcx);
+            let metadata_cgu_name = cgu_name_builder
+                .build_cgu_name(LOCAL_CRATE, &["crate"], Some("metadata"))
+                .as_str()
+                .to_string();
+
+            let mut metadata_artifact = faerie::Artifact::new(
+                crate::build_isa(tcx.sess, backend_config, true).triple().clone(),
+                metadata_cgu_name.clone(),
+            );
+            crate::metadata::write_metadata(tcx, &mut metadata_artifact);
+
+            let tmp_file = tcx
+                .output_filenames(LOCAL_CRATE)
+                .temp_path(OutputType::Metadata, Some(&metadata_cgu_name));
+
+            let obj = metadata_artifact.emit().unwrap();
+            std::fs::write(&tmp_file, obj).unwrap();
+
+            (metadata_cgu_name, tmp_file)
+        });
+
+        Some(CompiledModule {
+            name: metadata_cgu_name,
+            kind: ModuleKind::Metadata,
+            object: Some(tmp_file),
+            bytecode: None,
+            bytecode_compressed: None,
+        })
+    } else {
+        None
+    };
+
+    Box::new((CodegenResults {
+        crate_name: tcx.crate_name(LOCAL_CRATE),
+        modules,
+        allocator_module,
+        metadata_module,
+        crate_hash: tcx.crate_hash(LOCAL_CRATE),
+        metadata,
+        windows_subsystem: None, // Windows is not yet supported
+        linker_info: LinkerInfo::new(tcx),
+        crate_info: CrateInfo::new(tcx),
+    }, work_products))
+}
+
+// Adapted from https://github.com/rust-lang/rust/blob/303d8aff6092709edd4dbd35b1c88e9aa40bf6d8/src/librustc_codegen_ssa/base.rs#L922-L953
+fn determine_cgu_reuse<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    backend_config: &BackendConfig,
+    cgu: &CodegenUnit<'tcx>,
+) -> CguReuse {
+    if !tcx.dep_graph.is_fully_enabled() || !backend_config.enable_incr_cache {
+        return CguReuse::No;
+    }
+
+    let work_product_id = &cgu.work_product_id();
+    if tcx.dep_graph.previous_work_product(work_product_id).is_none() {
+        // We don't have anything cached for this CGU. This can happen
+        // if the CGU did not exist in the previous session.
+        return CguReuse::No;
+    }
+
+    // Try to mark the CGU as green. If it we can do so, it means that nothing
+    // affecting the object file has changed and we can re-use a cached version.
+    let dep_node = cgu.codegen_dep_node(tcx);
+    assert!(
+        !tcx.dep_graph.dep_node_exists(&dep_node),
+        "CompileCodegenUnit dep-node for CGU `{}` already exists before marking.",
+        cgu.name()
+    );
+
+    if tcx.dep_graph.try_mark_green(tcx, &dep_node).is_some() {
+        CguReuse::PreLto
+    } else {
+        CguReuse::No
+    }
+}
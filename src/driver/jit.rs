@@ -0,0 +1,1330 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use rustc::ich::Fingerprint;
+use rustc::mir::interpret::{ConstValue, GlobalId};
+use rustc::mir::visit::{PlaceContext, Visitor};
+use rustc::mir::mono::{Linkage as RLinkage, Visibility};
+use rustc::session::config::DebugInfo;
+use rustc_data_structures::stable_hasher::{HashStable, StableHasher};
+use rustc_codegen_ssa::CrateInfo;
+
+use cranelift_simplejit::{SimpleJITBackend, SimpleJITBuilder};
+
+use crate::constant::ConstantCx;
+use crate::prelude::*;
+use crate::CodegenMode;
+
+use super::jit_linker::JitLinker;
+
+thread_local! {
+    /// The state of the lazy JIT. Only set while a lazy `JitHandle` exists, so that
+    /// `__clif_jit_fn` can add functions to the module.
+    static LAZY_JIT_STATE: RefCell<Option<LazyJitState>> = RefCell::new(None);
+}
+
+struct LazyJitState {
+    module: Module<SimpleJITBackend>,
+    backend_config: BackendConfig,
+    constants_cx: ConstantCx,
+    /// The slots of all stubs by the symbol name of their function.
+    slots: FxHashMap<String, *mut LazyJitSlot>,
+    /// Shared by all slots. See `LazyJitSlot::requests`.
+    requests: &'static JitRequests,
+    /// Incremented by every hot reload. Used to give the code of every reload unique names.
+    generation: u32,
+    /// Kept for the handles created by hot reloads.
+    program_args: ProgramArgs,
+}
+
+/// The state of a single lazily compiled function. Every stub references its own slot.
+#[repr(C)]
+struct LazyJitSlot {
+    /// The address of the compiled function, or null when it hasn't been compiled yet. This must
+    /// be the first field, as the stubs load it from offset 0.
+    code: AtomicPtr<u8>,
+    /// The lifetime is a lie. `translate_lazy_fn` lifts it back to the lifetime of the current
+    /// `TyCtxt` before using it. `None` when the function has been removed by a hot reload.
+    instance: Option<Instance<'static>>,
+    /// The hash of everything `code` depends on. Used to find changed functions when hot
+    /// reloading. See `mir_hash`.
+    mir_hash: Option<Fingerprint>,
+    /// Shared by all slots of a JIT and leaked together with them.
+    requests: &'static JitRequests,
+}
+
+/// Functions can only be translated on the thread owning the `TyCtxt`. Stubs called on other
+/// threads send a request through this channel to it instead. `None` when the owning thread isn't
+/// inside `JitHandle::run_on_new_thread`.
+type JitRequests = Mutex<Option<mpsc::Sender<JitRequest>>>;
+
+enum JitRequest {
+    /// Translate the function of the slot and reply with its address.
+    Translate(*mut LazyJitSlot, mpsc::Sender<usize>),
+    /// The thread started by `JitHandle::run_on_new_thread` finished.
+    Finished,
+}
+
+// The slot is only accessed by the thread owning the `TyCtxt` while handling the request.
+unsafe impl Send for JitRequest {}
+
+/// Sends `JitRequest::Finished` when dropped, even when the thread panicked.
+struct NotifyFinished(mpsc::Sender<JitRequest>);
+
+impl Drop for NotifyFinished {
+    fn drop(&mut self) {
+        let _ = self.0.send(JitRequest::Finished);
+    }
+}
+
+/// A thread started by `JitHandle::spawn`.
+pub struct JitThread<R> {
+    thread: std::thread::JoinHandle<R>,
+    receiver: mpsc::Receiver<JitRequest>,
+    /// `None` when not in lazy mode.
+    requests: Option<&'static JitRequests>,
+    /// Requests received while no session could handle them. See `defer_until_reload`.
+    deferred: Vec<JitRequest>,
+}
+
+impl<R> JitThread<R> {
+    /// Wait for the thread after it sent `JitRequest::Finished` and return its result.
+    fn join(self) -> R {
+        if let Some(requests) = self.requests {
+            *requests.lock().unwrap() = None;
+        }
+
+        match self.thread.join() {
+            Ok(res) => res,
+            Err(err) => std::panic::resume_unwind(err),
+        }
+    }
+}
+
+/// The default stack size of the main thread on Linux. The program expects at least this much, as
+/// it normally runs on the main thread.
+const PROGRAM_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+pub fn run_jit(tcx: TyCtxt<'_>, backend_config: &BackendConfig) -> ! {
+    let lazy = backend_config.codegen_mode == CodegenMode::JitLazy;
+
+    let jit_handle = JitHandle::new(tcx, backend_config);
+
+    let finalized_main = jit_handle
+        .lookup_symbol("main")
+        .unwrap_or_else(|| tcx.sess.fatal("No main function found to run in JIT mode"));
+
+    println!("Rustc codegen cranelift will JIT run the executable, because -Cllvm-args=mode=jit was passed");
+
+    let f: extern "C" fn(c_int, *const *const c_char) -> c_int =
+        unsafe { ::std::mem::transmute(finalized_main) };
+
+    // The arguments of the rustc invocation of this session.
+    let rustc_args = std::env::args().collect::<Vec<_>>();
+
+    let program_args = jit_handle.program_args;
+    let run_main = move || {
+        // A failing libtest harness exits the process itself with exit code 101.
+        f(program_args.argc, program_args.argv.as_ptr())
+    };
+
+    if lazy {
+        // Keep this thread free to translate the functions called by the program.
+        let thread = jit_handle.spawn(run_main);
+        let ret = match &backend_config.jit_reload_trigger {
+            Some(reload_trigger) => {
+                jit_handle.serve_with_hot_reload(thread, reload_trigger, &rustc_args)
+            }
+            None => {
+                let ret = jit_handle.run_until_finished(thread);
+                drop(jit_handle);
+                ret
+            }
+        };
+        std::process::exit(ret);
+    }
+
+    let ret = run_main();
+    drop(jit_handle);
+    std::process::exit(ret);
+}
+
+/// How often the modification time of the reload trigger is checked.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The file passed as `jit-reload-trigger`.
+struct ReloadTrigger {
+    path: PathBuf,
+    last_modified: Option<SystemTime>,
+}
+
+impl ReloadTrigger {
+    fn new(path: PathBuf) -> Self {
+        let last_modified = Self::modified(&path);
+        ReloadTrigger {
+            path,
+            last_modified,
+        }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Whether the file has been modified since the last call.
+    fn poll(&mut self) -> bool {
+        let modified = Self::modified(&self.path);
+        if modified == self.last_modified {
+            return false;
+        }
+        self.last_modified = modified;
+        true
+    }
+}
+
+/// Everything needed to continue serving the program in the next compilation session.
+struct HotReloadState<R> {
+    lazy_jit_state: LazyJitState,
+    perf_state: Option<crate::perf_map::PerfState>,
+    reload_trigger: ReloadTrigger,
+    /// The result of the program once it finished, otherwise the thread running it.
+    program: Result<R, JitThread<R>>,
+}
+
+// rustc runs every session on a thread of its own, but the state is only used by one thread at a
+// time. `JitHandle::serve_with_hot_reload` waits until the session finished.
+unsafe impl<R: Send> Send for HotReloadState<R> {}
+
+struct HotReloadCallbacks<R> {
+    state: Option<HotReloadState<R>>,
+    /// Whether the crate compiled and the session served the program.
+    reloaded: bool,
+}
+
+impl<R: Send> rustc_driver::Callbacks for HotReloadCallbacks<R> {
+    fn after_analysis(
+        &mut self,
+        compiler: &rustc_interface::interface::Compiler,
+    ) -> rustc_driver::Compilation {
+        compiler.session().abort_if_errors();
+
+        let HotReloadState {
+            lazy_jit_state,
+            perf_state,
+            mut reload_trigger,
+            program,
+        } = self.state.take().unwrap();
+        let thread = match program {
+            Err(thread) => thread,
+            Ok(_) => unreachable!("reloaded after the program finished"),
+        };
+        LAZY_JIT_STATE.with(|state| *state.borrow_mut() = Some(lazy_jit_state));
+        crate::perf_map::restore_state(perf_state);
+
+        let (program, lazy_jit_state) =
+            compiler.global_ctxt().unwrap().peek_mut().enter(|tcx| {
+                let jit_handle = JitHandle::reload_lazy_jit_state(tcx);
+                let program = jit_handle.serve_until_reload(thread, &mut reload_trigger);
+                (program, jit_handle.into_lazy_jit_state())
+            });
+
+        self.state = Some(HotReloadState {
+            lazy_jit_state,
+            perf_state: crate::perf_map::take_state(),
+            reload_trigger,
+            program,
+        });
+        self.reloaded = true;
+        rustc_driver::Compilation::Stop
+    }
+}
+
+/// Wait for the next modification of the reload trigger after the crate failed to compile. There
+/// is no session to translate functions with in the meantime, so the translation requests of the
+/// program are deferred until the next session serves it.
+fn defer_until_reload<R>(
+    mut thread: JitThread<R>,
+    reload_trigger: &mut ReloadTrigger,
+) -> Result<R, JitThread<R>> {
+    loop {
+        match thread.receiver.recv_timeout(RELOAD_POLL_INTERVAL) {
+            Ok(JitRequest::Finished) => return Ok(thread.join()),
+            Ok(request) => thread.deferred.push(request),
+            Err(_) => {}
+        }
+        if reload_trigger.poll() {
+            return Err(thread);
+        }
+    }
+}
+
+/// The `argc` and `argv` the program is run with.
+#[derive(Copy, Clone)]
+struct ProgramArgs {
+    argc: c_int,
+    /// Terminated by a null pointer, as POSIX requires. Leaked, see `JitLinker::link`.
+    argv: &'static [*const c_char],
+}
+
+// The arguments are never modified.
+unsafe impl Send for ProgramArgs {}
+
+impl ProgramArgs {
+    fn new(tcx: TyCtxt<'_>, backend_config: &BackendConfig) -> Self {
+        let rustc_args = std::env::args().collect::<Vec<_>>();
+
+        let mut args = vec![program_name(tcx, &rustc_args)];
+        args.extend(backend_config.jit_args.iter().cloned());
+        let argv = args
+            .into_iter()
+            .map(|arg| CString::new(arg).unwrap().into_raw() as *const c_char)
+            .chain(std::iter::once(std::ptr::null()))
+            .collect::<Vec<_>>();
+        ProgramArgs {
+            argc: (argv.len() - 1) as c_int,
+            argv: Box::leak(argv.into_boxed_slice()),
+        }
+    }
+}
+
+/// `argv[0]` of the program. libtest harnesses built with `-Cpanic=abort` execute it with
+/// `__RUST_TEST_INVOKE` set to the name of a test to run every test in a new process, so for
+/// harnesses it is a script running the harness as a normal executable. See `write_test_script`.
+#[cfg(unix)]
+fn program_name(tcx: TyCtxt<'_>, rustc_args: &[String]) -> String {
+    if tcx.sess.opts.test {
+        write_test_script(tcx, rustc_args)
+    } else {
+        tcx.crate_name(LOCAL_CRATE).as_str().to_string()
+    }
+}
+
+/// Running tests in a new process is only supported on Unix.
+#[cfg(not(unix))]
+fn program_name(tcx: TyCtxt<'_>, _rustc_args: &[String]) -> String {
+    tcx.crate_name(LOCAL_CRATE).as_str().to_string()
+}
+
+/// Write a script which builds the test harness as executable in AOT mode by repeating the rustc
+/// invocation `rustc_args`, and then executes it. The harness is only built by the first test run
+/// in a new process and shared by all others. Returns the path of the script.
+#[cfg(unix)]
+fn write_test_script(tcx: TyCtxt<'_>, rustc_args: &[String]) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let quote = |arg: &str| format!("'{}'", arg.replace('\'', "'\\''"));
+
+    let output_filenames = tcx.output_filenames(LOCAL_CRATE);
+    let script_path = output_filenames.with_extension("jit-test.sh");
+    let harness_path = output_filenames.with_extension("jit-test");
+    // Left behind by a previous run, possibly of different code.
+    let _ = std::fs::remove_file(&harness_path);
+    let _ = std::fs::remove_dir(output_filenames.with_extension("jit-test.lock"));
+
+    // The last `mode` in `-Cllvm-args` wins.
+    let mut build = quote(&std::env::current_exe().unwrap().to_string_lossy());
+    for arg in rustc_args.iter().skip(1) {
+        build.push(' ');
+        build.push_str(&quote(arg));
+    }
+    build.push_str(" -Cllvm-args=mode=aot --emit \"link=$harness.tmp\"");
+
+    let script = format!(
+        "#!/bin/sh\n\
+         harness={harness}\n\
+         if [ ! -x \"$harness\" ]; then\n    \
+             while ! mkdir \"$harness.lock\" 2>/dev/null; do sleep 0.1; done\n    \
+             if [ ! -x \"$harness\" ]; then\n        \
+                 {build} >&2 && mv \"$harness.tmp\" \"$harness\"\n    \
+             fi\n    \
+             rmdir \"$harness.lock\"\n    \
+             [ -x \"$harness\" ] || exit 101\n\
+         fi\n\
+         exec \"$harness\" \"$@\"\n",
+        harness = quote(&harness_path.to_string_lossy()),
+        build = build,
+    );
+
+    let res = std::fs::write(&script_path, script).and_then(|()| {
+        std::fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755))
+    });
+    if let Err(err) = res {
+        tcx.sess.fatal(&format!(
+            "Failed to write {} to run tests in JIT mode: {}",
+            script_path.display(),
+            err
+        ));
+    }
+
+    script_path.to_string_lossy().into_owned()
+}
+
+/// A crate compiled in JIT mode. Functions can be looked up by symbol name or `DefId` and called
+/// as often as needed. The compiled code is freed when the handle is dropped, so function
+/// pointers must not be used after that.
+///
+/// In lazy mode functions are translated on their first call on the thread owning the `TyCtxt` of
+/// the handle. Functions first called on other threads are only translated while the owning thread
+/// is inside `run_on_new_thread`. Only one lazy handle can exist per thread.
+pub struct JitHandle<'tcx> {
+    tcx: TyCtxt<'tcx>,
+    /// `None` in lazy mode, as the module lives in `LAZY_JIT_STATE` then.
+    module: Option<Module<SimpleJITBackend>>,
+    /// All functions defined by the module, including the lazy stubs.
+    functions: FxHashMap<String, FuncId>,
+    /// Already passed to the `.init_array` functions of the rlibs loaded by the `JitLinker`.
+    program_args: ProgramArgs,
+}
+
+impl<'tcx> JitHandle<'tcx> {
+    /// Compile all functions of the local crate, or only stubs for them when the codegen mode of
+    /// `backend_config` is `JitLazy`. Errors are reported through the session and abort
+    /// compilation.
+    pub fn new(tcx: TyCtxt<'tcx>, backend_config: &BackendConfig) -> Self {
+        let lazy = backend_config.codegen_mode == CodegenMode::JitLazy;
+
+        let (imported_symbols, jit_linker) = load_imported_symbols_for_jit(tcx);
+        let dylib_symbols = imported_symbols
+            .iter()
+            .cloned()
+            .collect::<FxHashMap<String, *const u8>>();
+
+        let mut jit_builder = SimpleJITBuilder::with_isa(
+            crate::build_isa(tcx.sess, backend_config, false),
+            cranelift_module::default_libcall_names(),
+        );
+        jit_builder.symbols(imported_symbols);
+        jit_builder.symbols(jit_linker.exported_symbols());
+        jit_builder.symbol("__clif_jit_fn", __clif_jit_fn as *const u8);
+        let mut jit_module: Module<SimpleJITBackend> = Module::new(jit_builder);
+        assert_eq!(pointer_ty(tcx), jit_module.target_config().pointer_type());
+
+        if let Some(perf_mode) = backend_config.jit_perf {
+            crate::perf_map::enable(tcx.sess, perf_mode);
+        }
+
+        // In lazy mode every function gets its own debug context when it is translated.
+        let mut debug = if lazy {
+            None
+        } else {
+            new_debug_context(tcx, backend_config, &jit_module)
+        };
+
+        let mut slots = FxHashMap::default();
+        // Referenced by the slots of the lazy stubs, so it is leaked like them.
+        let requests: &'static JitRequests = Box::leak(Box::new(Mutex::new(None)));
+        let (constants_cx, functions) = codegen_cgus(
+            tcx,
+            &mut jit_module,
+            backend_config,
+            &mut debug,
+            &mut slots,
+            requests,
+        );
+        crate::allocator::codegen(tcx, &mut jit_module);
+        jit_module.finalize_definitions();
+
+        tcx.sess.abort_if_errors();
+
+        if let Some(debug) = &mut debug {
+            super::jit_debug::register_debuginfo(tcx, &jit_module, debug);
+        }
+
+        crate::perf_map::flush(tcx.sess, |name| finalized_function(&jit_module, name));
+
+        // Upstream rlibs may reference symbols defined by the local crate, like the allocator shim.
+        let local_exports = tcx
+            .exported_symbols(LOCAL_CRATE)
+            .iter()
+            .map(|&(symbol, _level)| symbol.symbol_name(tcx).name.as_str().to_string())
+            .collect::<FxHashSet<String>>();
+        let program_args = ProgramArgs::new(tcx, backend_config);
+        jit_linker.link(tcx, program_args.argc, program_args.argv.as_ptr(), |name| {
+            if let Some(&addr) = dylib_symbols.get(name) {
+                return Some(addr);
+            }
+            if !local_exports.contains(name) {
+                return None;
+            }
+            match jit_module.get_name(name)? {
+                FuncOrDataId::Func(func_id) => Some(jit_module.get_finalized_function(func_id)),
+                FuncOrDataId::Data(data_id) => {
+                    Some(jit_module.get_finalized_data(data_id).0 as *const u8)
+                }
+            }
+        });
+
+        let module = if lazy {
+            LAZY_JIT_STATE.with(|lazy_jit_state| {
+                let mut lazy_jit_state = lazy_jit_state.borrow_mut();
+                assert!(
+                    lazy_jit_state.is_none(),
+                    "only one lazy JIT can exist per thread"
+                );
+                *lazy_jit_state = Some(LazyJitState {
+                    module: jit_module,
+                    backend_config: backend_config.clone(),
+                    constants_cx,
+                    slots,
+                    requests,
+                    generation: 0,
+                    program_args,
+                });
+            });
+            None
+        } else {
+            Some(jit_module)
+        };
+
+        JitHandle {
+            tcx,
+            module,
+            functions,
+            program_args,
+        }
+    }
+
+    /// Get a pointer to the function with the given symbol name. The caller is responsible for
+    /// transmuting it to a function pointer with the correct signature.
+    pub fn lookup_symbol(&self, name: &str) -> Option<*const u8> {
+        let func_id = *self.functions.get(name)?;
+        Some(match &self.module {
+            Some(module) => module.get_finalized_function(func_id),
+            None => LAZY_JIT_STATE.with(|lazy_jit_state| {
+                lazy_jit_state
+                    .borrow()
+                    .as_ref()
+                    .unwrap()
+                    .module
+                    .get_finalized_function(func_id)
+            }),
+        })
+    }
+
+    /// Get a pointer to the given instance, when it has been codegened as part of the local crate.
+    pub fn lookup_instance(&self, instance: Instance<'tcx>) -> Option<*const u8> {
+        self.lookup_symbol(&*self.tcx.symbol_name(instance).name.as_str())
+    }
+
+    /// Get a pointer to the non-generic function `def_id`.
+    pub fn lookup_def_id(&self, def_id: DefId) -> Option<*const u8> {
+        if self
+            .tcx
+            .generics_of(def_id)
+            .requires_monomorphization(self.tcx)
+        {
+            return None;
+        }
+        self.lookup_instance(Instance::mono(self.tcx, def_id))
+    }
+
+    /// Run `f` on a new thread. In lazy mode the current thread translates the functions called
+    /// by `f` and by the threads it spawns until `f` returns. Calling functions which haven't been
+    /// translated yet after that aborts the process.
+    pub fn run_on_new_thread<R: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> R + Send + 'static,
+    ) -> R {
+        self.run_until_finished(self.spawn(f))
+    }
+
+    /// Translate the functions called by `thread` until it finished and return its result.
+    pub fn run_until_finished<R>(&self, thread: JitThread<R>) -> R {
+        match self.serve(thread, None) {
+            Ok(res) => res,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Start running `f` on a new thread. Use `serve` to translate the functions it calls in lazy
+    /// mode.
+    pub fn spawn<R: Send + 'static>(&self, f: impl FnOnce() -> R + Send + 'static) -> JitThread<R> {
+        let requests = match self.module {
+            Some(_) => None,
+            None => Some(LAZY_JIT_STATE.with(|lazy_jit_state| {
+                lazy_jit_state.borrow().as_ref().unwrap().requests
+            })),
+        };
+
+        let (tx, rx) = mpsc::channel();
+        if let Some(requests) = requests {
+            *requests.lock().unwrap() = Some(tx.clone());
+        }
+
+        let thread = std::thread::Builder::new()
+            .name("main".to_string())
+            .stack_size(PROGRAM_STACK_SIZE)
+            .spawn(move || {
+                let _notify_finished = NotifyFinished(tx);
+                f()
+            })
+            .unwrap();
+
+        JitThread {
+            thread,
+            receiver: rx,
+            requests,
+            deferred: Vec::new(),
+        }
+    }
+
+    /// Translate the functions called by `thread` and the threads it spawned until it finished
+    /// and return its result. When `timeout` elapses first, `thread` is returned instead, so the
+    /// caller can do other work before continuing to serve it.
+    pub fn serve<R>(
+        &self,
+        mut thread: JitThread<R>,
+        timeout: Option<Duration>,
+    ) -> Result<R, JitThread<R>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut deferred = std::mem::replace(&mut thread.deferred, Vec::new()).into_iter();
+        loop {
+            let request = match (deferred.next(), deadline) {
+                (Some(request), _) => request,
+                (None, Some(deadline)) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match thread.receiver.recv_timeout(timeout) {
+                        Ok(request) => request,
+                        Err(_) => return Err(thread),
+                    }
+                }
+                // `NotifyFinished` guarantees that a `Finished` request arrives eventually.
+                (None, None) => thread.receiver.recv().unwrap(),
+            };
+            match request {
+                JitRequest::Translate(slot, reply) => {
+                    let code = translate_lazy_fn(self.tcx, slot);
+                    // The requesting thread may have been killed in the meantime.
+                    let _ = reply.send(code as usize);
+                }
+                JitRequest::Finished => return Ok(thread.join()),
+            }
+        }
+    }
+
+    /// Serve `thread` until it finished or `reload_trigger` has been modified.
+    fn serve_until_reload<R>(
+        &self,
+        mut thread: JitThread<R>,
+        reload_trigger: &mut ReloadTrigger,
+    ) -> Result<R, JitThread<R>> {
+        loop {
+            thread = match self.serve(thread, Some(RELOAD_POLL_INTERVAL)) {
+                Ok(res) => return Ok(res),
+                Err(thread) => thread,
+            };
+            if reload_trigger.poll() {
+                return Err(thread);
+            }
+        }
+    }
+
+    /// Like `run_until_finished`, but compile the crate again every time the modification time of
+    /// `reload_trigger` changes and continue with the new session like `reload` does. The sessions
+    /// are created by running rustc with `args`, which have to be the arguments of the session of
+    /// this handle. Every new session returns before the next one starts, so only the session of
+    /// this handle and the latest one are alive at any time.
+    ///
+    /// When the crate fails to compile, the errors are reported and the program keeps running.
+    /// Calls of functions which haven't been translated yet wait until the crate compiles again.
+    pub fn serve_with_hot_reload<R: Send + 'static>(
+        self,
+        thread: JitThread<R>,
+        reload_trigger: &Path,
+        args: &[String],
+    ) -> R {
+        if self.module.is_some() {
+            self.tcx
+                .sess
+                .fatal("Hot reloading is only supported in lazy JIT mode");
+        }
+
+        let mut reload_trigger = ReloadTrigger::new(reload_trigger.to_path_buf());
+        let thread = match self.serve_until_reload(thread, &mut reload_trigger) {
+            Ok(res) => return res,
+            Err(thread) => thread,
+        };
+        let mut state = HotReloadState {
+            lazy_jit_state: self.into_lazy_jit_state(),
+            perf_state: crate::perf_map::take_state(),
+            reload_trigger,
+            program: Err(thread),
+        };
+
+        loop {
+            let mut callbacks = HotReloadCallbacks {
+                state: Some(state),
+                reloaded: false,
+            };
+            let _ = rustc_driver::catch_fatal_errors(|| {
+                rustc_driver::run_compiler(args, &mut callbacks, None, None)
+            });
+            state = callbacks
+                .state
+                .take()
+                .expect("the session didn't hand the JIT state back");
+
+            let program = match state.program {
+                Err(thread) if !callbacks.reloaded => {
+                    defer_until_reload(thread, &mut state.reload_trigger)
+                }
+                program => program,
+            };
+            match program {
+                Ok(res) => {
+                    state.lazy_jit_state.module.finish();
+                    return res;
+                }
+                Err(thread) => state.program = Err(thread),
+            }
+        }
+    }
+
+    /// Switch to a new compilation session of the same crate without restarting the program, for
+    /// example after the source has been edited. Functions are translated again on their next
+    /// call when their MIR, the layout of a type they use or the value of a constant they
+    /// reference changed. New functions and statics are added and removed functions abort the
+    /// process when they are called. Existing statics keep their current value, so the state of
+    /// the program is preserved. Only supported in lazy mode, as the slots of the stubs act as the
+    /// indirection table through which all calls go.
+    ///
+    /// The MIR hash includes spans, so functions which only moved within the source are translated
+    /// again too. Changing the layout of a type used by an existing static is not supported.
+    pub fn reload<'new>(mut self, tcx: TyCtxt<'new>) -> JitHandle<'new> {
+        if self.module.is_some() {
+            tcx.sess.fatal("Hot reloading is only supported in lazy JIT mode");
+        }
+
+        // Ownership of the lazy JIT state is transferred to the new handle, so don't drop it.
+        self.functions = FxHashMap::default();
+        std::mem::forget(self);
+
+        let jit_handle = JitHandle::reload_lazy_jit_state(tcx);
+        tcx.sess.abort_if_errors();
+        jit_handle
+    }
+
+    /// Take the lazy JIT state out of the thread without freeing the compiled code, so it can be
+    /// moved to the thread of another session.
+    fn into_lazy_jit_state(mut self) -> LazyJitState {
+        self.functions = FxHashMap::default();
+        std::mem::forget(self);
+        LAZY_JIT_STATE
+            .with(|lazy_jit_state| lazy_jit_state.borrow_mut().take())
+            .unwrap()
+    }
+
+    /// Create a handle for the lazy JIT state of the current thread after updating it for `tcx`.
+    fn reload_lazy_jit_state(tcx: TyCtxt<'tcx>) -> Self {
+        let (functions, program_args) = LAZY_JIT_STATE.with(|lazy_jit_state| {
+            let mut lazy_jit_state = lazy_jit_state.borrow_mut();
+            let lazy_jit_state = lazy_jit_state.as_mut().unwrap();
+            let functions =
+                super::time("reload mono items", || reload_mono_items(tcx, lazy_jit_state));
+            (functions, lazy_jit_state.program_args)
+        });
+
+        JitHandle {
+            tcx,
+            module: None,
+            functions,
+            program_args,
+        }
+    }
+}
+
+impl Drop for JitHandle<'_> {
+    fn drop(&mut self) {
+        let module = self.module.take().unwrap_or_else(|| {
+            LAZY_JIT_STATE
+                .with(|lazy_jit_state| lazy_jit_state.borrow_mut().take())
+                .unwrap()
+                .module
+        });
+        module.finish();
+    }
+}
+
+/// Called by the stub of a function the first time it is invoked. Returns the address of the
+/// translated function, so the stub can forward the call. Calls on other threads than the one
+/// owning the `TyCtxt` are forwarded to it, as functions can only be translated there.
+extern "C" fn __clif_jit_fn(slot: *mut LazyJitSlot) -> *const u8 {
+    let is_jit_thread = LAZY_JIT_STATE.with(|lazy_jit_state| lazy_jit_state.borrow().is_some());
+    if is_jit_thread {
+        return rustc::ty::tls::with(|tcx| translate_lazy_fn(tcx, slot));
+    }
+
+    // Panicking is not allowed in an `extern "C"` function, so abort on errors.
+    let (reply_tx, reply_rx) = mpsc::channel();
+    let sent = match unsafe { (*slot).requests }.lock() {
+        Ok(requests) => match &*requests {
+            Some(requests) => requests.send(JitRequest::Translate(slot, reply_tx)).is_ok(),
+            None => false,
+        },
+        Err(_) => false,
+    };
+    let code = if sent { reply_rx.recv().ok() } else { None };
+    match code {
+        Some(code) => code as *const u8,
+        None => {
+            eprintln!(
+                "Lazy JIT stub called on another thread while the thread running the JIT doesn't \
+                 translate functions for other threads"
+            );
+            std::process::abort();
+        }
+    }
+}
+
+/// Translate the function of `slot` when that didn't happen yet and return its address.
+fn translate_lazy_fn(tcx: TyCtxt<'_>, slot: *mut LazyJitSlot) -> *const u8 {
+    let slot = unsafe { &mut *slot };
+    let code = slot.code.load(Ordering::Acquire);
+    if !code.is_null() {
+        // Another call of the same stub already compiled the function.
+        return code;
+    }
+
+    LAZY_JIT_STATE.with(|lazy_jit_state| {
+        let mut lazy_jit_state = lazy_jit_state.borrow_mut();
+        let lazy_jit_state = lazy_jit_state.as_mut().unwrap();
+
+        // lift is used to ensure the correct lifetime for instance.
+        let instance = match &slot.instance {
+            Some(instance) => tcx.lift(instance).unwrap(),
+            None => {
+                let slot: *const LazyJitSlot = slot;
+                let name = lazy_jit_state
+                    .slots
+                    .iter()
+                    .find(|&(_name, &other)| other as *const LazyJitSlot == slot)
+                    .map(|(name, _slot)| &**name)
+                    .unwrap_or("<unknown>");
+                eprintln!("Called function `{}`, which has been removed by a hot reload", name);
+                std::process::abort();
+            }
+        };
+
+        let (name, _sig) = get_function_name_and_sig(tcx, instance, false);
+        let generation = lazy_jit_state.generation;
+
+        let mut debug = new_debug_context(
+            tcx,
+            &lazy_jit_state.backend_config,
+            &lazy_jit_state.module,
+        );
+
+        let mut cx = CodegenCx::new(
+            tcx,
+            &mut lazy_jit_state.module,
+            &lazy_jit_state.backend_config,
+            debug.as_mut(),
+            None,
+        );
+        cx.constants_cx =
+            std::mem::replace(&mut lazy_jit_state.constants_cx, ConstantCx::default());
+
+        let mut func_id = None;
+        crate::unimpl::try_unimpl(tcx, MonoItem::Fn(instance).to_string(tcx, true), || {
+            // The symbol name itself is used by the stub, so define the real function under
+            // a different name.
+            func_id = Some(crate::base::trans_fn_with_symbol(
+                &mut cx,
+                instance,
+                &format!("{}.lazy_jit.{}", name, generation),
+                Linkage::Local,
+            ));
+        });
+
+        lazy_jit_state.constants_cx = cx.finalize();
+
+        if tcx.sess.has_errors() {
+            // Unwinding out of the stub is not possible. The errors have already been
+            // emitted, so just exit.
+            std::process::exit(1);
+        }
+
+        lazy_jit_state.module.finalize_definitions();
+        crate::perf_map::flush(tcx.sess, |name| {
+            finalized_function(&lazy_jit_state.module, name)
+        });
+        if let Some(debug) = &mut debug {
+            super::jit_debug::register_debuginfo(tcx, &lazy_jit_state.module, debug);
+        }
+        let code = lazy_jit_state
+            .module
+            .get_finalized_function(func_id.unwrap());
+        slot.mir_hash = Some(mir_hash(tcx, instance));
+        slot.code.store(code as *mut u8, Ordering::Release);
+        code
+    })
+}
+
+fn load_imported_symbols_for_jit(tcx: TyCtxt<'_>) -> (Vec<(String, *const u8)>, JitLinker) {
+    use rustc::middle::dependency_format::Linkage;
+
+    let mut dylib_paths = Vec::new();
+    let mut rlib_paths = Vec::new();
+
+    let crate_info = CrateInfo::new(tcx);
+    // Use the dependency format of the first crate type being compiled which links its
+    // dependencies. When there is none, like for rlibs, dependencies are linked dynamically when
+    // possible and statically otherwise.
+    let formats = tcx.dependency_formats(LOCAL_CRATE);
+    let data = tcx
+        .sess
+        .crate_types
+        .get()
+        .iter()
+        .filter_map(|crate_type| formats.iter().find(|(ty, _data)| ty == crate_type))
+        .map(|(_crate_type, data)| data)
+        .find(|data| !data.is_empty());
+    for &(cnum, _) in &crate_info.used_crates_dynamic {
+        let src = &crate_info.used_crate_source[&cnum];
+        let linkage = match data {
+            Some(data) => data[cnum.as_usize() - 1],
+            None if src.dylib.is_some() => Linkage::Dynamic,
+            None => Linkage::Static,
+        };
+        match linkage {
+            Linkage::NotLinked | Linkage::IncludedFromDylib => {}
+            Linkage::Static => match &src.rlib {
+                Some((path, _)) => rlib_paths.push(path.clone()),
+                None => tcx.sess.err(&format!(
+                    "Can't load crate `{}` in JIT mode, as its rlib wasn't found",
+                    tcx.crate_name(cnum)
+                )),
+            },
+            Linkage::Dynamic => match &src.dylib {
+                Some((path, _)) => dylib_paths.push(path.clone()),
+                None => tcx.sess.err(&format!(
+                    "Can't load crate `{}` in JIT mode, as its dylib wasn't found",
+                    tcx.crate_name(cnum)
+                )),
+            },
+        }
+    }
+
+    let mut imported_symbols = Vec::new();
+    for path in dylib_paths {
+        use object::Object;
+        let lib = libloading::Library::new(&path).unwrap();
+        let obj = std::fs::read(path).unwrap();
+        let obj = object::File::parse(&obj).unwrap();
+        imported_symbols.extend(obj.dynamic_symbols().filter_map(|(_idx, symbol)| {
+            let name = symbol.name().unwrap().to_string();
+            if name.is_empty() || !symbol.is_global() || symbol.is_undefined() {
+                return None;
+            }
+            let symbol: libloading::Symbol<*const u8> =
+                unsafe { lib.get(name.as_bytes()) }.unwrap();
+            Some((name, *symbol))
+        }));
+        std::mem::forget(lib)
+    }
+
+    tcx.sess.abort_if_errors();
+
+    (imported_symbols, JitLinker::load_rlibs(tcx, rlib_paths))
+}
+
+/// Returns the constants and all functions defined by the module. Functions with
+/// `#[target_feature]` attributes are translated using the baseline features of the target, as all
+/// functions of a `Module` share a single isa.
+fn codegen_cgus<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    module: &mut Module<SimpleJITBackend>,
+    backend_config: &BackendConfig,
+    debug: &mut Option<DebugContext<'tcx>>,
+    slots: &mut FxHashMap<String, *mut LazyJitSlot>,
+    requests: &'static JitRequests,
+) -> (ConstantCx, FxHashMap<String, FuncId>) {
+    let mono_items = all_mono_items(tcx);
+
+    let function_names = function_names(tcx, &mono_items);
+
+    let constants_cx = super::time("codegen mono items", || {
+        if backend_config.codegen_mode == CodegenMode::JitLazy {
+            codegen_lazy_stubs(tcx, module, backend_config, slots, requests, mono_items)
+        } else {
+            super::codegen_mono_items(tcx, module, backend_config, debug.as_mut(), None, mono_items)
+        }
+    });
+
+    crate::main_shim::maybe_create_entry_wrapper(tcx, module, None);
+
+    (constants_cx, defined_functions(module, function_names))
+}
+
+fn all_mono_items<'tcx>(tcx: TyCtxt<'tcx>) -> Vec<(MonoItem<'tcx>, (RLinkage, Visibility))> {
+    let (_, cgus) = tcx.collect_and_partition_mono_items(LOCAL_CRATE);
+    cgus.iter()
+        .map(|cgu| cgu.items_in_deterministic_order(tcx).into_iter())
+        .flatten()
+        .collect::<FxHashMap<_, (_, _)>>()
+        .into_iter()
+        .collect::<Vec<(_, (_, _))>>()
+}
+
+fn function_names<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    mono_items: &[(MonoItem<'tcx>, (RLinkage, Visibility))],
+) -> Vec<String> {
+    let mut function_names = mono_items
+        .iter()
+        .filter_map(|(mono_item, _)| match mono_item {
+            MonoItem::Fn(instance) => Some(tcx.symbol_name(*instance).name.as_str().to_string()),
+            MonoItem::Static(_) | MonoItem::GlobalAsm(_) => None,
+        })
+        .collect::<Vec<String>>();
+    function_names.push("main".to_string());
+    function_names
+}
+
+fn defined_functions(
+    module: &Module<SimpleJITBackend>,
+    function_names: Vec<String>,
+) -> FxHashMap<String, FuncId> {
+    // `main` is only defined when the crate has an entry function.
+    function_names
+        .into_iter()
+        .filter_map(|name| match module.get_name(&name) {
+            Some(FuncOrDataId::Func(func_id)) => Some((name, func_id)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Debuginfo is registered with debuggers through the GDB JIT interface, which is only
+/// implemented for ELF.
+fn new_debug_context<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    backend_config: &BackendConfig,
+    module: &Module<SimpleJITBackend>,
+) -> Option<DebugContext<'tcx>> {
+    if tcx.sess.opts.debuginfo != DebugInfo::None
+        && crate::target_triple(tcx.sess).binary_format == target_lexicon::BinaryFormat::Elf
+    {
+        Some(DebugContext::new(
+            tcx,
+            module.target_config().pointer_type().bytes() as u8,
+            backend_config,
+        ))
+    } else {
+        None
+    }
+}
+
+fn finalized_function(module: &Module<SimpleJITBackend>, name: &str) -> Option<*const u8> {
+    match module.get_name(name)? {
+        FuncOrDataId::Func(func_id) => Some(module.get_finalized_function(func_id)),
+        FuncOrDataId::Data(_) => None,
+    }
+}
+
+/// Hash everything the translation of `instance` depends on: its MIR, the layouts of the types it
+/// uses and the values of the constants and promoteds it references. When any of them changes, the
+/// function has to be translated again after a hot reload.
+fn mir_hash<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> Fingerprint {
+    let mut hcx = tcx.create_stable_hashing_context();
+    let mut hasher = StableHasher::new();
+    instance.hash_stable(&mut hcx, &mut hasher);
+    let mir = tcx.instance_mir(instance.def);
+    mir.hash_stable(&mut hcx, &mut hasher);
+
+    let monomorphize = |ty: Ty<'tcx>| {
+        tcx.subst_and_normalize_erasing_regions(instance.substs, ParamEnv::reveal_all(), &ty)
+    };
+
+    // Field projections only access types reachable from the types of the locals.
+    let mut tys = mir
+        .local_decls
+        .iter()
+        .map(|local_decl| monomorphize(local_decl.ty))
+        .collect::<Vec<Ty<'tcx>>>();
+    let mut seen_tys = FxHashSet::default();
+    while let Some(ty) = tys.pop() {
+        if !seen_tys.insert(ty) {
+            continue;
+        }
+        ty.hash_stable(&mut hcx, &mut hasher);
+        if let Ok(layout) = tcx.layout_of(ParamEnv::reveal_all().and(ty)) {
+            layout.details.hash_stable(&mut hcx, &mut hasher);
+        }
+        match ty.kind {
+            ty::Adt(adt_def, substs) => tys.extend(adt_def.all_fields().map(|field| {
+                tcx.normalize_erasing_regions(ParamEnv::reveal_all(), field.ty(tcx, substs))
+            })),
+            ty::Tuple(_) => tys.extend(ty.tuple_fields()),
+            ty::Array(ty, _) | ty::Slice(ty) => tys.push(ty),
+            ty::Ref(_, ty, _) | ty::RawPtr(TypeAndMut { ty, mutbl: _ }) => tys.push(ty),
+            ty::Closure(def_id, substs) => tys.extend(substs.upvar_tys(def_id, tcx)),
+            _ => {}
+        }
+    }
+
+    let mut consts = ConstDependencies {
+        tcx,
+        instance,
+        consts: Vec::new(),
+    };
+    consts.visit_body(mir);
+    for const_ in consts.consts {
+        const_.hash_stable(&mut hcx, &mut hasher);
+    }
+
+    hasher.finish()
+}
+
+/// Collects the values of all constants and promoteds referenced by a MIR body.
+struct ConstDependencies<'tcx> {
+    tcx: TyCtxt<'tcx>,
+    instance: Instance<'tcx>,
+    consts: Vec<&'tcx ty::Const<'tcx>>,
+}
+
+impl<'tcx> ConstDependencies<'tcx> {
+    fn add(&mut self, cid: GlobalId<'tcx>) {
+        // Errors are reported when the function is translated.
+        if let Ok(const_) = self.tcx.const_eval(ParamEnv::reveal_all().and(cid)) {
+            self.consts.push(const_);
+        }
+    }
+
+    fn monomorphize<T: TypeFoldable<'tcx>>(&self, value: &T) -> T {
+        self.tcx.subst_and_normalize_erasing_regions(
+            self.instance.substs,
+            ParamEnv::reveal_all(),
+            value,
+        )
+    }
+}
+
+impl<'tcx> Visitor<'tcx> for ConstDependencies<'tcx> {
+    fn visit_constant(&mut self, constant: &Constant<'tcx>, location: Location) {
+        if let ConstValue::Unevaluated(def_id, substs) = constant.literal.val {
+            let substs = self.monomorphize(&substs);
+            if let Some(instance) =
+                Instance::resolve(self.tcx, ParamEnv::reveal_all(), def_id, substs)
+            {
+                self.add(GlobalId {
+                    instance,
+                    promoted: None,
+                });
+            }
+        }
+        self.super_constant(constant, location);
+    }
+
+    fn visit_place(&mut self, place: &Place<'tcx>, context: PlaceContext, location: Location) {
+        if let PlaceBase::Static(static_) = &place.base {
+            if let StaticKind::Promoted(promoted, substs) = static_.kind {
+                let instance = Instance::new(static_.def_id, self.monomorphize(&substs));
+                self.add(GlobalId {
+                    instance,
+                    promoted: Some(promoted),
+                });
+            }
+        }
+        self.super_place(place, context, location);
+    }
+}
+
+/// Update the lazy JIT state for a new compilation session. Stubs are added for new functions and
+/// the slots of changed functions are reset, so they get translated again on their next call.
+fn reload_mono_items<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    lazy_jit_state: &mut LazyJitState,
+) -> FxHashMap<String, FuncId> {
+    let mono_items = all_mono_items(tcx);
+    let function_names = function_names(tcx, &mono_items);
+
+    lazy_jit_state.generation += 1;
+    let generation = lazy_jit_state.generation;
+
+    let LazyJitState {
+        module,
+        backend_config,
+        constants_cx,
+        slots,
+        requests,
+        generation: _,
+    } = lazy_jit_state;
+
+    let mut cx = CodegenCx::new(tcx, module, backend_config, None, None);
+    cx.constants_cx = ConstantCx::with_alloc_name_prefix(format!("reload{}.", generation));
+
+    // Functions which no longer exist can't be translated anymore. Calls through function pointers
+    // obtained before the reload abort with an error instead.
+    let new_function_names = function_names.iter().collect::<FxHashSet<&String>>();
+    for (name, &slot) in slots.iter() {
+        if !new_function_names.contains(name) {
+            let slot = unsafe { &mut *slot };
+            slot.code.store(std::ptr::null_mut(), Ordering::Release);
+            slot.instance = None;
+            slot.mir_hash = None;
+        }
+    }
+
+    for (mono_item, (linkage, visibility)) in mono_items {
+        crate::unimpl::try_unimpl(tcx, mono_item.to_string(tcx, true), || {
+            let linkage = crate::linkage::get_clif_linkage(mono_item, linkage, visibility);
+            match mono_item {
+                MonoItem::Fn(instance) => {
+                    let name = tcx.symbol_name(instance).name.as_str().to_string();
+                    match slots.get(&name) {
+                        Some(&slot) => {
+                            let slot = unsafe { &mut *slot };
+                            let removed = slot.instance.is_none();
+                            slot.instance = Some(unsafe {
+                                std::mem::transmute::<Instance<'tcx>, Instance<'static>>(instance)
+                            });
+                            if removed
+                                || slot.mir_hash.is_some()
+                                    && slot.mir_hash != Some(mir_hash(tcx, instance))
+                            {
+                                slot.code.store(std::ptr::null_mut(), Ordering::Release);
+                                slot.mir_hash = None;
+                            }
+                        }
+                        None => {
+                            let slot = codegen_lazy_stub(&mut cx, instance, linkage, *requests);
+                            slots.insert(name, slot);
+                        }
+                    }
+                }
+                MonoItem::Static(def_id) => {
+                    // Existing statics keep their value.
+                    let name = tcx.symbol_name(Instance::mono(tcx, def_id)).name.as_str();
+                    if cx.module.get_name(&*name).is_none() {
+                        super::trans_mono_item(&mut cx, mono_item, linkage);
+                    }
+                }
+                MonoItem::GlobalAsm(_) => super::trans_mono_item(&mut cx, mono_item, linkage),
+            }
+        });
+    }
+
+    *constants_cx = cx.finalize();
+    module.finalize_definitions();
+    crate::perf_map::flush(tcx.sess, |name| finalized_function(module, name));
+
+    defined_functions(module, function_names)
+}
+
+/// Define a stub for every function instead of translating it. Statics are still defined eagerly.
+fn codegen_lazy_stubs<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    module: &mut Module<SimpleJITBackend>,
+    backend_config: &BackendConfig,
+    slots: &mut FxHashMap<String, *mut LazyJitSlot>,
+    requests: &'static JitRequests,
+    mono_items: Vec<(MonoItem<'tcx>, (RLinkage, Visibility))>,
+) -> ConstantCx {
+    let mut cx = CodegenCx::new(tcx, module, backend_config, None, None);
+
+    for (mono_item, (linkage, visibility)) in mono_items {
+        crate::unimpl::try_unimpl(tcx, mono_item.to_string(tcx, true), || {
+            let linkage = crate::linkage::get_clif_linkage(mono_item, linkage, visibility);
+            match mono_item {
+                MonoItem::Fn(instance) => {
+                    let slot = codegen_lazy_stub(&mut cx, instance, linkage, requests);
+                    slots.insert(tcx.symbol_name(instance).name.as_str().to_string(), slot);
+                }
+                MonoItem::Static(_) | MonoItem::GlobalAsm(_) => {
+                    super::trans_mono_item(&mut cx, mono_item, linkage)
+                }
+            }
+        });
+    }
+
+    cx.finalize()
+}
+
+/// Define a stub for `instance`, which loads the address of the real function from its slot and
+/// calls it. When the slot is still empty, `__clif_jit_fn` is called to translate the function
+/// first. Returns the slot of the stub.
+fn codegen_lazy_stub<'tcx>(
+    cx: &mut CodegenCx<'_, 'tcx, SimpleJITBackend>,
+    instance: Instance<'tcx>,
+    linkage: Linkage,
+    requests: &'static JitRequests,
+) -> *mut LazyJitSlot {
+    let tcx = cx.tcx;
+    let pointer_type = cx.module.target_config().pointer_type();
+
+    let (name, sig) = get_function_name_and_sig(tcx, instance, false);
+    let func_id = cx.module.declare_function(&name, linkage, &sig).unwrap();
+
+    // The slot is leaked, as the stub references it for the rest of the execution.
+    let slot: *mut LazyJitSlot = Box::into_raw(Box::new(LazyJitSlot {
+        code: AtomicPtr::new(std::ptr::null_mut()),
+        instance: Some(unsafe {
+            std::mem::transmute::<Instance<'tcx>, Instance<'static>>(instance)
+        }),
+        mir_hash: None,
+        requests,
+    }));
+
+    let jit_fn_id = cx
+        .module
+        .declare_function(
+            "__clif_jit_fn",
+            Linkage::Import,
+            &Signature {
+                params: vec![AbiParam::new(pointer_type)],
+                returns: vec![AbiParam::new(pointer_type)],
+                call_conv: crate::default_call_conv(tcx.sess),
+            },
+        )
+        .unwrap();
+
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, 0), sig.clone());
+    {
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut bcx = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+
+        let entry_ebb = bcx.create_ebb();
+        let compile_ebb = bcx.create_ebb();
+        let call_ebb = bcx.create_ebb();
+
+        bcx.switch_to_block(entry_ebb);
+        let args = sig
+            .params
+            .iter()
+            .map(|param| bcx.append_ebb_param(entry_ebb, param.value_type))
+            .collect::<Vec<Value>>();
+        let slot_addr = bcx.ins().iconst(pointer_type, slot as i64);
+        let code = bcx.ins().load(pointer_type, MemFlags::new(), slot_addr, 0);
+        bcx.ins().brz(code, compile_ebb, &[]);
+        bcx.ins().jump(call_ebb, &[code]);
+
+        bcx.switch_to_block(compile_ebb);
+        let jit_fn = cx.module.declare_func_in_func(jit_fn_id, &mut bcx.func);
+        let call_inst = bcx.ins().call(jit_fn, &[slot_addr]);
+        let code = bcx.inst_results(call_inst)[0];
+        bcx.ins().jump(call_ebb, &[code]);
+
+        bcx.switch_to_block(call_ebb);
+        let code = bcx.append_ebb_param(call_ebb, pointer_type);
+        let sig_ref = bcx.import_signature(sig);
+        let call_inst = bcx.ins().call_indirect(sig_ref, code, &args);
+        let results = bcx.inst_results(call_inst).to_vec(); // Clone to prevent borrow error
+        bcx.ins().return_(&results);
+
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    cx.module.define_function(func_id, &mut ctx).unwrap();
+    crate::perf_map::record_function(&name, &ctx, cx.module.isa());
+
+    slot
+}
@@ -0,0 +1,277 @@
+//! Registration of JIT-compiled code with debuggers using the GDB JIT interface, which is
+//! supported by both gdb and lldb.
+//!
+//! For every batch of finalized functions an ELF object file is built in memory. It contains the
+//! DWARF sections of the functions with all addresses already resolved, a `.text` section without
+//! contents covering the functions and a symbol table. The object is then added to the linked list
+//! of `__jit_debug_descriptor` and `__jit_debug_register_code` is called, on which the debugger has
+//! set a breakpoint.
+
+use std::ptr;
+
+use cranelift_simplejit::SimpleJITBackend;
+
+use crate::prelude::*;
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // The debugger sets a breakpoint on this function. Make sure calls to it are not removed.
+    unsafe {
+        ptr::read_volatile(&__jit_debug_descriptor.action_flag);
+    }
+}
+
+#[no_mangle]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+/// Emit the debuginfo of all functions in `debug_context` and register it with the debugger.
+/// Must be called after the functions have been finalized.
+pub fn register_debuginfo(
+    tcx: TyCtxt<'_>,
+    module: &Module<SimpleJITBackend>,
+    debug_context: &mut DebugContext<'_>,
+) {
+    let lookup_symbol = |name: &str| -> Option<u64> {
+        match module.get_name(name)? {
+            FuncOrDataId::Func(func_id) => Some(module.get_finalized_function(func_id) as u64),
+            FuncOrDataId::Data(data_id) => Some(module.get_finalized_data(data_id).0 as u64),
+        }
+    };
+
+    let functions = debug_context
+        .functions()
+        .into_iter()
+        .filter_map(|(name, size)| Some((name.to_string(), lookup_symbol(name)?, size)))
+        .collect::<Vec<_>>();
+    if functions.is_empty() {
+        return;
+    }
+
+    let sections = debug_context.emit_in_memory(lookup_symbol);
+
+    if let Some(obj) = build_debug_object(tcx, &functions, sections) {
+        register_object(obj);
+    }
+}
+
+/// Build an ELF relocatable object describing the functions at their final addresses. Returns
+/// `None` for targets other than 64bit little endian ELF.
+fn build_debug_object(
+    tcx: TyCtxt<'_>,
+    functions: &[(String, u64, u64)],
+    debug_sections: Vec<(gimli::SectionId, Vec<u8>)>,
+) -> Option<Vec<u8>> {
+    let triple = crate::target_triple(tcx.sess);
+    let machine: u16 = match triple.architecture {
+        target_lexicon::Architecture::X86_64 => 62,   // EM_X86_64
+        target_lexicon::Architecture::Aarch64 => 183, // EM_AARCH64
+        _ => return None,
+    };
+    if triple.binary_format != target_lexicon::BinaryFormat::Elf {
+        return None;
+    }
+
+    let text_start = functions.iter().map(|&(_, addr, _)| addr).min().unwrap();
+    let text_end = functions
+        .iter()
+        .map(|&(_, addr, size)| addr + size)
+        .max()
+        .unwrap();
+
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; 24]; // The null symbol
+    for (name, addr, size) in functions {
+        let name_offset = strtab.len() as u32;
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+
+        symtab.extend_from_slice(&name_offset.to_le_bytes());
+        symtab.push(0x12); // STB_GLOBAL, STT_FUNC
+        symtab.push(0); // STV_DEFAULT
+        symtab.extend_from_slice(&1u16.to_le_bytes()); // .text
+        symtab.extend_from_slice(&(addr - text_start).to_le_bytes());
+        symtab.extend_from_slice(&size.to_le_bytes());
+    }
+
+    struct SectionHeader {
+        name: &'static str,
+        kind: u32,
+        flags: u64,
+        addr: u64,
+        size: u64,
+        data: Vec<u8>,
+        link: u32,
+        info: u32,
+        align: u64,
+        entsize: u64,
+    }
+
+    let mut sections = vec![SectionHeader {
+        name: ".text",
+        kind: 8, // SHT_NOBITS
+        flags: 0x6, // SHF_ALLOC | SHF_EXECINSTR
+        addr: text_start,
+        size: text_end - text_start,
+        data: Vec::new(),
+        link: 0,
+        info: 0,
+        align: 16,
+        entsize: 0,
+    }];
+    for (id, data) in debug_sections {
+        sections.push(SectionHeader {
+            name: id.name(),
+            kind: 1, // SHT_PROGBITS
+            flags: 0,
+            addr: 0,
+            size: data.len() as u64,
+            data,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        });
+    }
+    let strtab_index = sections.len() as u32 + 2;
+    sections.push(SectionHeader {
+        name: ".symtab",
+        kind: 2, // SHT_SYMTAB
+        flags: 0,
+        addr: 0,
+        size: symtab.len() as u64,
+        data: symtab,
+        link: strtab_index,
+        info: 1, // Index of the first non-local symbol
+        align: 8,
+        entsize: 24,
+    });
+    sections.push(SectionHeader {
+        name: ".strtab",
+        kind: 3, // SHT_STRTAB
+        flags: 0,
+        addr: 0,
+        size: strtab.len() as u64,
+        data: strtab,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+
+    let mut shstrtab = vec![0u8];
+    let mut section_names = Vec::new();
+    for section in sections.iter().map(|section| section.name).chain(Some(".shstrtab")) {
+        section_names.push(shstrtab.len() as u32);
+        shstrtab.extend_from_slice(section.as_bytes());
+        shstrtab.push(0);
+    }
+    let shstrtab_index = sections.len() as u16 + 1;
+    sections.push(SectionHeader {
+        name: ".shstrtab",
+        kind: 3, // SHT_STRTAB
+        flags: 0,
+        addr: 0,
+        size: shstrtab.len() as u64,
+        data: shstrtab,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+
+    // The ELF header is followed by the section contents and the section header table.
+    let mut obj = vec![0u8; 64];
+    let mut offsets = Vec::new();
+    for section in &sections {
+        while obj.len() as u64 % section.align != 0 {
+            obj.push(0);
+        }
+        offsets.push(obj.len() as u64);
+        obj.extend_from_slice(&section.data);
+    }
+    while obj.len() % 8 != 0 {
+        obj.push(0);
+    }
+    let section_headers_offset = obj.len() as u64;
+
+    obj.extend_from_slice(&[0u8; 64]); // The null section
+    for ((section, offset), name) in sections.iter().zip(offsets).zip(section_names) {
+        obj.extend_from_slice(&name.to_le_bytes());
+        obj.extend_from_slice(&section.kind.to_le_bytes());
+        obj.extend_from_slice(&section.flags.to_le_bytes());
+        obj.extend_from_slice(&section.addr.to_le_bytes());
+        obj.extend_from_slice(&offset.to_le_bytes());
+        obj.extend_from_slice(&section.size.to_le_bytes());
+        obj.extend_from_slice(&section.link.to_le_bytes());
+        obj.extend_from_slice(&section.info.to_le_bytes());
+        obj.extend_from_slice(&section.align.to_le_bytes());
+        obj.extend_from_slice(&section.entsize.to_le_bytes());
+    }
+
+    let header = &mut obj[0..64];
+    header[0..4].copy_from_slice(b"\x7fELF");
+    header[4] = 2; // ELFCLASS64
+    header[5] = 1; // ELFDATA2LSB
+    header[6] = 1; // EV_CURRENT
+    header[16..18].copy_from_slice(&1u16.to_le_bytes()); // ET_REL
+    header[18..20].copy_from_slice(&machine.to_le_bytes());
+    header[20..24].copy_from_slice(&1u32.to_le_bytes()); // EV_CURRENT
+    header[40..48].copy_from_slice(&section_headers_offset.to_le_bytes());
+    header[52..54].copy_from_slice(&64u16.to_le_bytes()); // e_ehsize
+    header[58..60].copy_from_slice(&64u16.to_le_bytes()); // e_shentsize
+    header[60..62].copy_from_slice(&(sections.len() as u16 + 1).to_le_bytes()); // e_shnum
+    header[62..64].copy_from_slice(&shstrtab_index.to_le_bytes());
+
+    Some(obj)
+}
+
+/// Add `obj` to the list of registered objects and notify the debugger. The object is leaked, as
+/// the debugger may read it for as long as the code it describes exists. The JIT only runs on a
+/// single thread, so no locking is necessary.
+fn register_object(obj: Vec<u8>) {
+    let obj = Box::leak(obj.into_boxed_slice());
+    unsafe {
+        let entry = Box::into_raw(Box::new(JitCodeEntry {
+            next_entry: __jit_debug_descriptor.first_entry,
+            prev_entry: ptr::null_mut(),
+            symfile_addr: obj.as_ptr(),
+            symfile_size: obj.len() as u64,
+        }));
+        if !(*entry).next_entry.is_null() {
+            (*(*entry).next_entry).prev_entry = entry;
+        }
+        __jit_debug_descriptor.first_entry = entry;
+
+        __jit_debug_descriptor.relevant_entry = entry;
+        __jit_debug_descriptor.action_flag = JIT_REGISTER_FN;
+        __jit_debug_register_code();
+
+        __jit_debug_descriptor.relevant_entry = ptr::null_mut();
+        __jit_debug_descriptor.action_flag = JIT_NOACTION;
+    }
+}
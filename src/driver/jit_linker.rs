@@ -0,0 +1,860 @@
+//! A minimal in-memory linker, which is used by the JIT to load the object files contained in the
+//! rlibs of statically linked upstream crates.
+//!
+//! Loading happens in two phases. [JitLinker::load_rlibs] copies all sections into memory, so the
+//! addresses of all symbols defined by the rlibs are known before the JIT module is finalized.
+//! After the JIT module is finalized [JitLinker::link] applies the relocations, which may
+//! reference symbols defined by the JIT module itself, like the allocator shim.
+//!
+//! Only x86_64 ELF object files are supported.
+//!
+//! Thread locals are emulated: every object gets its own block of thread local storage, which is
+//! allocated on the first access from a thread by our own implementation of `__tls_get_addr`.
+//! This supports the general dynamic and local dynamic TLS models used by position independent
+//! code, but not the initial exec and local exec models, as they require a static TLS offset.
+
+use std::alloc::Layout;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::io::Read;
+use std::os::raw::{c_char, c_int};
+use std::path::PathBuf;
+
+use object::{
+    Object, ObjectSection, Relocation, RelocationKind, RelocationTarget, SectionIndex,
+    SectionKind, SymbolKind,
+};
+use rustc_codegen_ssa::{METADATA_FILENAME, RLIB_BYTECODE_EXTENSION};
+
+use crate::prelude::*;
+
+// Relaxable variants of `R_X86_64_GOTPCREL`. We always provide a real GOT entry, so they can be
+// handled exactly like `R_X86_64_GOTPCREL`.
+const R_X86_64_GOTPCRELX: u32 = 41;
+const R_X86_64_REX_GOTPCRELX: u32 = 42;
+
+// Thread local relocations.
+const R_X86_64_DTPOFF64: u32 = 17;
+const R_X86_64_TLSGD: u32 = 19;
+const R_X86_64_TLSLD: u32 = 20;
+const R_X86_64_DTPOFF32: u32 = 21;
+const R_X86_64_GOTTPOFF: u32 = 22;
+const R_X86_64_TPOFF32: u32 = 23;
+
+const PAGE_SIZE: usize = 4096;
+
+/// `jmp qword ptr [rip + 0]` followed by the 8 byte absolute address of the target.
+const STUB_SIZE: usize = 16;
+const STUB_PREFIX: [u8; 6] = [0xff, 0x25, 0x00, 0x00, 0x00, 0x00];
+
+// The opcodes `LoadedObject::redirect_pc32` looks for.
+const OPCODE_CALL: u8 = 0xe8;
+const OPCODE_JMP: u8 = 0xe9;
+const OPCODE_LEA: u8 = 0x8d;
+const OPCODE_MOV_LOAD: u8 = 0x8b;
+
+/// Signature of the functions in `.init_array`, which glibc passes `argc`, `argv` and `envp`.
+type InitFn = extern "C" fn(c_int, *const *const c_char, *const *const c_char);
+
+extern "C" {
+    static environ: *const *const c_char;
+}
+
+/// The initial contents of the thread local storage of an object. Leaked, as TLS indices reference
+/// it for the rest of the execution.
+struct TlsTemplate {
+    image: Vec<u8>,
+    align: usize,
+}
+
+/// The argument of `__tls_get_addr`, which is stored in a pair of GOT entries.
+#[repr(C)]
+struct TlsIndex {
+    template: *const TlsTemplate,
+    offset: u64,
+}
+
+thread_local! {
+    /// The thread local storage of all objects on the current thread, by the address of their
+    /// template.
+    static TLS_BLOCKS: RefCell<FxHashMap<usize, *mut u8>> = RefCell::new(FxHashMap::default());
+}
+
+/// Replaces `__tls_get_addr` for the loaded objects.
+extern "C" fn jit_tls_get_addr(index: *const TlsIndex) -> *mut u8 {
+    let index = unsafe { &*index };
+    let new_block = || {
+        // The block is leaked, as destructors of thread locals may still access it after
+        // `TLS_BLOCKS` has been destroyed.
+        let template = unsafe { &*index.template };
+        let layout =
+            Layout::from_size_align(std::cmp::max(template.image.len(), 1), template.align)
+                .unwrap();
+        unsafe {
+            let block = std::alloc::alloc(layout);
+            std::ptr::copy_nonoverlapping(template.image.as_ptr(), block, template.image.len());
+            block
+        }
+    };
+    let block = TLS_BLOCKS
+        .try_with(|blocks| {
+            *blocks
+                .borrow_mut()
+                .entry(index.template as usize)
+                .or_insert_with(new_block)
+        })
+        .unwrap_or_else(|_| new_block());
+    unsafe { block.add(index.offset as usize) }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Segment {
+    Text,
+    ReadOnly,
+    Writable,
+}
+
+struct LoadedObject {
+    /// `<rlib path>(<member name>)`, used in diagnostics.
+    name: String,
+    data: Vec<u8>,
+    memory: *mut u8,
+    text_size: usize,
+    read_only_size: usize,
+    writable_size: usize,
+    section_addrs: FxHashMap<SectionIndex, *mut u8>,
+    /// `.init_array` sections with their priority and size.
+    init_arrays: Vec<(u32, SectionIndex, usize)>,
+
+    /// Null when the object has no thread locals.
+    tls_template: *const TlsTemplate,
+    /// The offsets of the TLS sections within the thread local storage of the object.
+    tls_offsets: FxHashMap<SectionIndex, u64>,
+
+    /// GOT entries for `R_X86_64_GOTPCREL` relocations and TLS indices. Placed at the end of the
+    /// read-only part.
+    got: *mut u64,
+    got_len: usize,
+    got_entries: FxHashMap<u64, *mut u64>,
+    tls_indices: FxHashMap<(usize, u64), *mut u64>,
+    /// Jump stubs for calls to targets further than 2GB away. Placed at the end of the text part.
+    stubs: *mut u8,
+    stub_entries: FxHashMap<u64, *mut u8>,
+}
+
+pub struct JitLinker {
+    objects: Vec<LoadedObject>,
+    /// All global symbols defined by the loaded objects. When a symbol is defined multiple times,
+    /// the first definition wins.
+    symbols: FxHashMap<String, *const u8>,
+    /// The entries of `symbols` which are common symbols, with their size. Like for the linker,
+    /// the largest common symbol wins and a real definition overrides all of them.
+    common_symbols: FxHashMap<String, u64>,
+    /// All global thread locals defined by the loaded objects, with the offset within the thread
+    /// local storage of the defining object.
+    tls_symbols: FxHashMap<String, (*const TlsTemplate, u64)>,
+}
+
+impl JitLinker {
+    pub fn load_rlibs(tcx: TyCtxt<'_>, rlibs: Vec<PathBuf>) -> Self {
+        let mut linker = JitLinker {
+            objects: Vec::new(),
+            symbols: FxHashMap::default(),
+            common_symbols: FxHashMap::default(),
+            tls_symbols: FxHashMap::default(),
+        };
+
+        if rlibs.is_empty() {
+            return linker;
+        }
+
+        let triple = crate::target_triple(tcx.sess);
+        if triple.architecture != target_lexicon::Architecture::X86_64
+            || triple.binary_format != target_lexicon::BinaryFormat::Elf
+        {
+            tcx.sess.fatal(&format!(
+                "Loading rlibs in JIT mode is not supported for {}. Try -Cprefer-dynamic.",
+                triple
+            ));
+        }
+
+        for rlib in rlibs {
+            let mut archive = ar::Archive::new(std::fs::File::open(&rlib).unwrap());
+            while let Some(entry) = archive.next_entry() {
+                let mut entry = entry.unwrap();
+                let member_name = String::from_utf8(entry.header().identifier().to_vec()).unwrap();
+
+                // Ignore bytecode/metadata files, like `ArArchiveBuilder::add_rlib` does.
+                if member_name.ends_with(RLIB_BYTECODE_EXTENSION) || member_name == METADATA_FILENAME
+                {
+                    continue;
+                }
+
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                linker.load_object(tcx, format!("{}({})", rlib.display(), member_name), data);
+            }
+        }
+
+        tcx.sess.abort_if_errors();
+
+        linker
+    }
+
+    /// The global symbols defined by all loaded objects. These are passed to the JIT module, so
+    /// JIT-compiled code can call into the rlibs.
+    pub fn exported_symbols(&self) -> Vec<(String, *const u8)> {
+        self.symbols
+            .iter()
+            .map(|(name, &addr)| (name.clone(), addr))
+            .collect()
+    }
+
+    fn load_object(&mut self, tcx: TyCtxt<'_>, name: String, data: Vec<u8>) {
+        let (memory, sizes, section_addrs, init_arrays, tls, got, stubs) = {
+            let obj = match object::File::parse(&data) {
+                Ok(obj) => obj,
+                Err(err) => {
+                    tcx.sess
+                        .err(&format!("Failed to parse {} for JIT: {}", name, err));
+                    return;
+                }
+            };
+
+            // Compute the layout of the sections within the three segments.
+            let mut segment_sizes = [0usize; 3];
+            let mut section_offsets = Vec::new();
+            let mut init_arrays = Vec::new();
+            let mut tls_image = Vec::new();
+            let mut tls_align = 1;
+            let mut tls_offsets = FxHashMap::default();
+            let mut reloc_count = 0;
+            for section in obj.sections() {
+                let init_array_priority = section.name().and_then(init_array_priority);
+                let align = std::cmp::max(section.align(), 1) as usize;
+                let segment = match section.kind() {
+                    SectionKind::Text => Segment::Text,
+                    SectionKind::ReadOnlyData | SectionKind::ReadOnlyString => Segment::ReadOnly,
+                    SectionKind::Data | SectionKind::UninitializedData => Segment::Writable,
+                    kind @ SectionKind::Tls | kind @ SectionKind::UninitializedTls => {
+                        let offset = (tls_image.len() + align - 1) / align * align;
+                        tls_image.resize(offset, 0);
+                        if kind == SectionKind::Tls {
+                            tls_image.extend_from_slice(&section.data());
+                        } else {
+                            tls_image.resize(offset + section.size() as usize, 0);
+                        }
+                        tls_align = std::cmp::max(tls_align, align);
+                        tls_offsets.insert(section.index(), offset as u64);
+                        reloc_count += section.relocations().count();
+                        continue;
+                    }
+                    _ if init_array_priority.is_some() => Segment::Writable,
+                    _ => continue,
+                };
+                let size = &mut segment_sizes[segment as usize];
+                let offset = (*size + align - 1) / align * align;
+                *size = offset + section.size() as usize;
+                section_offsets.push((section.index(), segment, offset));
+                if let Some(priority) = init_array_priority {
+                    init_arrays.push((priority, section.index(), section.size() as usize));
+                }
+                reloc_count += section.relocations().count();
+            }
+
+            // Common symbols get zero initialized storage at the end of the writable segment,
+            // like the linker allocates them in `.bss`.
+            let mut common_offsets = Vec::new();
+            for (_idx, symbol) in obj.symbols() {
+                if !symbol.is_global() || !is_common(&symbol) {
+                    continue;
+                }
+                let symbol_name = match symbol.name() {
+                    Some(symbol_name) if !symbol_name.is_empty() => symbol_name,
+                    _ => continue,
+                };
+                // The value of a common symbol is its alignment.
+                let align = std::cmp::max(symbol.address(), 1) as usize;
+                let size = &mut segment_sizes[Segment::Writable as usize];
+                let offset = (*size + align - 1) / align * align;
+                *size = offset + symbol.size() as usize;
+                common_offsets.push((symbol_name, offset, symbol.size()));
+            }
+
+            // Every relocation needs at most one GOT entry, one TLS index taking two GOT entries
+            // or one stub.
+            let text_size = page_align(segment_sizes[0] + reloc_count * STUB_SIZE);
+            let got_offset = (segment_sizes[1] + 7) / 8 * 8;
+            let read_only_size = page_align(got_offset + reloc_count * 16);
+            let writable_size = page_align(segment_sizes[2]);
+
+            let total_size = text_size + read_only_size + writable_size;
+            if total_size == 0 {
+                return;
+            }
+
+            let memory = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    total_size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            assert_ne!(memory, libc::MAP_FAILED, "Failed to allocate memory for {}", name);
+            let memory = memory as *mut u8;
+
+            let segment_base = |segment: Segment| unsafe {
+                match segment {
+                    Segment::Text => memory,
+                    Segment::ReadOnly => memory.add(text_size),
+                    Segment::Writable => memory.add(text_size + read_only_size),
+                }
+            };
+
+            let mut section_addrs = FxHashMap::default();
+            for (index, segment, offset) in section_offsets {
+                let addr = unsafe { segment_base(segment).add(offset) };
+                let section = obj.section_by_index(index).unwrap();
+                if section.kind() != SectionKind::UninitializedData {
+                    // `mmap` already zeroed the memory for `.bss` like sections.
+                    let section_data = section.data();
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(
+                            section_data.as_ptr(),
+                            addr,
+                            section_data.len(),
+                        );
+                    }
+                }
+                section_addrs.insert(index, addr);
+            }
+
+            let tls_template = if tls_offsets.is_empty() {
+                std::ptr::null()
+            } else {
+                Box::into_raw(Box::new(TlsTemplate {
+                    image: tls_image,
+                    align: tls_align,
+                })) as *const TlsTemplate
+            };
+
+            for (_idx, symbol) in obj.symbols() {
+                if !symbol.is_global() || symbol.is_undefined() {
+                    continue;
+                }
+                let symbol_name = match symbol.name() {
+                    Some(symbol_name) if !symbol_name.is_empty() => symbol_name,
+                    _ => continue,
+                };
+                if let Some(&tls_offset) = symbol
+                    .section_index()
+                    .and_then(|index| tls_offsets.get(&index))
+                {
+                    self.tls_symbols
+                        .entry(symbol_name.to_string())
+                        .or_insert((tls_template, tls_offset + symbol.address()));
+                    continue;
+                }
+                let section_addr = match symbol
+                    .section_index()
+                    .and_then(|index| section_addrs.get(&index))
+                {
+                    Some(&section_addr) => section_addr,
+                    None => continue,
+                };
+                let addr = unsafe { section_addr.add(symbol.address() as usize) };
+                if self.common_symbols.remove(symbol_name).is_some()
+                    || !self.symbols.contains_key(symbol_name)
+                {
+                    self.symbols.insert(symbol_name.to_string(), addr as *const u8);
+                }
+            }
+
+            for (symbol_name, offset, size) in common_offsets {
+                let replace = match self.common_symbols.get(symbol_name) {
+                    Some(&common_size) => size > common_size,
+                    None => !self.symbols.contains_key(symbol_name),
+                };
+                if replace {
+                    let addr = unsafe { segment_base(Segment::Writable).add(offset) };
+                    self.symbols.insert(symbol_name.to_string(), addr as *const u8);
+                    self.common_symbols.insert(symbol_name.to_string(), size);
+                }
+            }
+
+            let got = unsafe { segment_base(Segment::ReadOnly).add(got_offset) } as *mut u64;
+            let stubs = unsafe { segment_base(Segment::Text).add(segment_sizes[0]) };
+
+            (
+                memory,
+                (text_size, read_only_size, writable_size),
+                section_addrs,
+                init_arrays,
+                (tls_template, tls_offsets),
+                got,
+                stubs,
+            )
+        };
+
+        self.objects.push(LoadedObject {
+            name,
+            data,
+            memory,
+            text_size: sizes.0,
+            read_only_size: sizes.1,
+            writable_size: sizes.2,
+            section_addrs,
+            init_arrays,
+            tls_template: tls.0,
+            tls_offsets: tls.1,
+            got,
+            got_len: 0,
+            got_entries: FxHashMap::default(),
+            tls_indices: FxHashMap::default(),
+            stubs,
+            stub_entries: FxHashMap::default(),
+        });
+    }
+
+    /// Apply the relocations of all loaded objects, make their memory executable and run the
+    /// functions in their `.init_array` sections with the `argc` and `argv` of the program.
+    /// libstd keeps these to implement `std::env::args`, so `argv` must be terminated by a null
+    /// pointer and stay valid for the rest of the execution. `resolve` is called for every symbol
+    /// that isn't defined by one of the loaded objects.
+    pub fn link(
+        self,
+        tcx: TyCtxt<'_>,
+        argc: c_int,
+        argv: *const *const c_char,
+        resolve: impl Fn(&str) -> Option<*const u8>,
+    ) {
+        let JitLinker {
+            mut objects,
+            symbols,
+            common_symbols: _,
+            tls_symbols,
+        } = self;
+
+        let resolve = |name: &str| {
+            if name == "__tls_get_addr" {
+                return Some(jit_tls_get_addr as *const u8);
+            }
+            symbols
+                .get(name)
+                .cloned()
+                .or_else(|| resolve(name))
+                .or_else(|| {
+                    let c_name = CString::new(name).unwrap();
+                    let addr = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c_name.as_ptr()) };
+                    if addr.is_null() {
+                        None
+                    } else {
+                        Some(addr as *const u8)
+                    }
+                })
+        };
+
+        for object in &mut objects {
+            object.apply_relocations(tcx, &resolve, &tls_symbols);
+        }
+
+        tcx.sess.abort_if_errors();
+
+        let mut init_functions = Vec::new();
+        for object in objects {
+            init_functions.extend(object.init_functions());
+            // The memory is intentionally leaked, as the loaded code may run until the process
+            // exits.
+            object.protect();
+        }
+
+        // Like the linker orders `.init_array` sections, functions with an explicit priority run
+        // first. The sort is stable, so the objects are otherwise initialized in load order.
+        init_functions.sort_by_key(|&(priority, _)| priority);
+        for (_priority, init_function) in init_functions {
+            init_function(argc, argv, unsafe { environ });
+        }
+    }
+}
+
+impl LoadedObject {
+    fn apply_relocations(
+        &mut self,
+        tcx: TyCtxt<'_>,
+        resolve: &impl Fn(&str) -> Option<*const u8>,
+        tls_symbols: &FxHashMap<String, (*const TlsTemplate, u64)>,
+    ) {
+        let data = std::mem::replace(&mut self.data, Vec::new());
+        let obj = object::File::parse(&data).unwrap();
+
+        for section in obj.sections() {
+            let is_text = section.kind() == SectionKind::Text;
+            let section_addr = match self.section_addrs.get(&section.index()) {
+                Some(&section_addr) => section_addr,
+                // Relocations of `.tdata` are applied to the template, which is copied for every
+                // thread.
+                None => match self.tls_offsets.get(&section.index()) {
+                    Some(&tls_offset) => unsafe {
+                        let template = self.tls_template as *mut TlsTemplate;
+                        (*template).image.as_mut_ptr().add(tls_offset as usize)
+                    },
+                    None => continue,
+                },
+            };
+
+            for (offset, reloc) in section.relocations() {
+                let place = unsafe { section_addr.add(offset as usize) };
+
+                match reloc.kind() {
+                    RelocationKind::Other(r_type @ R_X86_64_DTPOFF64)
+                    | RelocationKind::Other(r_type @ R_X86_64_TLSGD..=R_X86_64_TPOFF32) => {
+                        self.apply_tls_relocation(tcx, &obj, tls_symbols, r_type, &reloc, place);
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                let target = match reloc.target() {
+                    RelocationTarget::Symbol(index) => {
+                        let symbol = obj.symbol_by_index(index).unwrap();
+                        let section_addr = symbol
+                            .section_index()
+                            .and_then(|index| self.section_addrs.get(&index).cloned());
+                        match section_addr {
+                            Some(section_addr) if !symbol.is_undefined() => unsafe {
+                                if symbol.kind() == SymbolKind::Section {
+                                    section_addr
+                                } else {
+                                    section_addr.add(symbol.address() as usize)
+                                }
+                            },
+                            _ => {
+                                let symbol_name = symbol.name().unwrap_or("");
+                                match resolve(symbol_name) {
+                                    Some(addr) => addr as *mut u8,
+                                    None => {
+                                        tcx.sess.err(&format!(
+                                            "Undefined symbol `{}` referenced by {}",
+                                            symbol_name, self.name
+                                        ));
+                                        continue;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    RelocationTarget::Section(index) => match self.section_addrs.get(&index) {
+                        Some(&section_addr) => section_addr,
+                        None => {
+                            tcx.sess.err(&format!(
+                                "Relocation in {} references a section that wasn't loaded",
+                                self.name
+                            ));
+                            continue;
+                        }
+                    },
+                } as u64;
+
+                let addend = if reloc.has_implicit_addend() {
+                    match reloc.size() {
+                        32 => unsafe { std::ptr::read_unaligned(place as *const i32) as i64 },
+                        64 => unsafe { std::ptr::read_unaligned(place as *const i64) },
+                        _ => 0,
+                    }
+                } else {
+                    reloc.addend()
+                };
+                let pc = place as u64;
+
+                let value = match (reloc.kind(), reloc.size()) {
+                    (RelocationKind::Absolute, 64) => {
+                        unsafe {
+                            std::ptr::write_unaligned(
+                                place as *mut u64,
+                                target.wrapping_add(addend as u64),
+                            );
+                        }
+                        continue;
+                    }
+                    (RelocationKind::Absolute, 32) => {
+                        // `R_X86_64_32` is zero extended, unlike `R_X86_64_32S`.
+                        match u32::try_from(target.wrapping_add(addend as u64)) {
+                            Ok(value) => unsafe {
+                                std::ptr::write_unaligned(place as *mut u32, value)
+                            },
+                            Err(_) => self.report_out_of_range(tcx, place),
+                        }
+                        continue;
+                    }
+                    (RelocationKind::AbsoluteSigned, 32) => target as i64 + addend,
+                    (RelocationKind::Relative, 32) => {
+                        let value = target as i64 + addend - pc as i64;
+                        if i32::try_from(value).is_ok() || !is_text {
+                            value
+                        } else {
+                            self.redirect_pc32(place, offset, target, addend).unwrap_or(value)
+                        }
+                    }
+                    (RelocationKind::PltRelative, 32) => {
+                        let value = target as i64 + addend - pc as i64;
+                        if i32::try_from(value).is_ok() {
+                            value
+                        } else {
+                            self.stub_for(target) as i64 + addend - pc as i64
+                        }
+                    }
+                    (RelocationKind::GotRelative, 32)
+                    | (RelocationKind::Other(R_X86_64_GOTPCRELX), _)
+                    | (RelocationKind::Other(R_X86_64_REX_GOTPCRELX), _) => {
+                        self.got_entry_for(target) as i64 + addend - pc as i64
+                    }
+                    (kind, size) => {
+                        tcx.sess.err(&format!(
+                            "Unsupported relocation {:?} of size {} in {}",
+                            kind, size, self.name
+                        ));
+                        continue;
+                    }
+                };
+
+                self.write_i32(tcx, place, value);
+            }
+        }
+    }
+
+    /// Thread locals are accessed through a TLS index referencing the template of the defining
+    /// object, which `jit_tls_get_addr` resolves to the storage of the current thread.
+    fn apply_tls_relocation(
+        &mut self,
+        tcx: TyCtxt<'_>,
+        obj: &object::File<'_>,
+        tls_symbols: &FxHashMap<String, (*const TlsTemplate, u64)>,
+        r_type: u32,
+        reloc: &Relocation,
+        place: *mut u8,
+    ) {
+        let pc = place as i64;
+        let addend = reloc.addend();
+
+        if r_type == R_X86_64_TLSLD {
+            // The address of the storage of this object, to which `R_X86_64_DTPOFF32` offsets are
+            // added.
+            let tls_index = self.tls_index_for(self.tls_template, 0);
+            self.write_i32(tcx, place, tls_index as i64 + addend - pc);
+            return;
+        }
+        if r_type == R_X86_64_GOTTPOFF || r_type == R_X86_64_TPOFF32 {
+            tcx.sess.err(&format!(
+                "{} uses the initial exec or local exec TLS model, which is not supported for \
+                 rlibs in JIT mode",
+                self.name
+            ));
+            return;
+        }
+
+        let tls_location = match reloc.target() {
+            RelocationTarget::Symbol(index) => {
+                let symbol = obj.symbol_by_index(index).unwrap();
+                let tls_offset = symbol
+                    .section_index()
+                    .and_then(|index| self.tls_offsets.get(&index));
+                match tls_offset {
+                    Some(&tls_offset) if !symbol.is_undefined() => {
+                        Some((self.tls_template, tls_offset + symbol.address()))
+                    }
+                    _ => symbol
+                        .name()
+                        .and_then(|name| tls_symbols.get(name))
+                        .cloned(),
+                }
+            }
+            RelocationTarget::Section(index) => self
+                .tls_offsets
+                .get(&index)
+                .map(|&tls_offset| (self.tls_template, tls_offset)),
+        };
+        let (template, tls_offset) = match tls_location {
+            Some(tls_location) => tls_location,
+            None => {
+                tcx.sess.err(&format!(
+                    "{} references a thread local which isn't defined by an rlib, which is not \
+                     supported in JIT mode",
+                    self.name
+                ));
+                return;
+            }
+        };
+
+        match r_type {
+            R_X86_64_TLSGD => {
+                let tls_index = self.tls_index_for(template, tls_offset);
+                self.write_i32(tcx, place, tls_index as i64 + addend - pc);
+            }
+            R_X86_64_DTPOFF32 => self.write_i32(tcx, place, tls_offset as i64 + addend),
+            R_X86_64_DTPOFF64 => unsafe {
+                std::ptr::write_unaligned(place as *mut i64, tls_offset as i64 + addend);
+            },
+            _ => tcx.sess.err(&format!(
+                "Unsupported thread local relocation {} in {}",
+                r_type, self.name
+            )),
+        }
+    }
+
+    /// `R_X86_64_PC32` has no variant going through a stub or the GOT like `R_X86_64_PLT32` and
+    /// `R_X86_64_GOTPCREL`, so for targets further than 2GB away the instruction at `place` is
+    /// inspected instead. Calls and jumps go through a stub and `lea` is turned into a load of a
+    /// GOT entry, the inverse of the relaxation linkers apply to `R_X86_64_REX_GOTPCRELX`. Returns
+    /// the new value of the relocation, or `None` for other instructions, like loads and stores,
+    /// which can't be redirected.
+    fn redirect_pc32(
+        &mut self,
+        place: *mut u8,
+        offset: u64,
+        target: u64,
+        addend: i64,
+    ) -> Option<i64> {
+        let pc = place as i64;
+        unsafe {
+            if offset >= 1 && (*place.sub(1) == OPCODE_CALL || *place.sub(1) == OPCODE_JMP) {
+                return Some(self.stub_for(target) as i64 + addend - pc);
+            }
+            // `lea reg, [rip + disp32]`, where the ModRM byte selects rip relative addressing. The
+            // instruction ends with the displacement, so the addend is `-4` plus the offset
+            // within the target, which has to be part of the GOT entry.
+            if offset >= 2 && *place.sub(2) == OPCODE_LEA && *place.sub(1) & 0xc7 == 0x05 {
+                *place.sub(2) = OPCODE_MOV_LOAD;
+                let got_entry = self.got_entry_for(target.wrapping_add((addend + 4) as u64));
+                return Some(got_entry as i64 - 4 - pc);
+            }
+        }
+        None
+    }
+
+    fn write_i32(&self, tcx: TyCtxt<'_>, place: *mut u8, value: i64) {
+        match i32::try_from(value) {
+            Ok(value) => unsafe { std::ptr::write_unaligned(place as *mut i32, value) },
+            Err(_) => self.report_out_of_range(tcx, place),
+        }
+    }
+
+    fn report_out_of_range(&self, tcx: TyCtxt<'_>, place: *mut u8) {
+        tcx.sess.err(&format!(
+            "Relocation target out of range at address {:p} of {}",
+            place, self.name
+        ));
+    }
+
+    fn alloc_got_entries(&mut self, values: &[u64]) -> *mut u64 {
+        unsafe {
+            let entries = self.got.add(self.got_len);
+            std::ptr::copy_nonoverlapping(values.as_ptr(), entries, values.len());
+            self.got_len += values.len();
+            entries
+        }
+    }
+
+    fn got_entry_for(&mut self, target: u64) -> *mut u64 {
+        if let Some(&entry) = self.got_entries.get(&target) {
+            return entry;
+        }
+        let entry = self.alloc_got_entries(&[target]);
+        self.got_entries.insert(target, entry);
+        entry
+    }
+
+    fn tls_index_for(&mut self, template: *const TlsTemplate, tls_offset: u64) -> *mut u64 {
+        let key = (template as usize, tls_offset);
+        if let Some(&tls_index) = self.tls_indices.get(&key) {
+            return tls_index;
+        }
+        // Laid out like `TlsIndex`.
+        let tls_index = self.alloc_got_entries(&[template as u64, tls_offset]);
+        self.tls_indices.insert(key, tls_index);
+        tls_index
+    }
+
+    /// The functions in the `.init_array` sections with their priority. Must be called after the
+    /// relocations have been applied.
+    fn init_functions(&self) -> Vec<(u32, InitFn)> {
+        let mut init_functions = Vec::new();
+        for &(priority, index, size) in &self.init_arrays {
+            let entries = self.section_addrs[&index] as *const u64;
+            for i in 0..size / 8 {
+                let entry = unsafe { std::ptr::read_unaligned(entries.add(i)) };
+                // 0 and -1 are used as terminators by some toolchains.
+                if entry != 0 && entry != u64::max_value() {
+                    init_functions.push((priority, unsafe {
+                        std::mem::transmute::<u64, InitFn>(entry)
+                    }));
+                }
+            }
+        }
+        init_functions
+    }
+
+    fn stub_for(&mut self, target: u64) -> *mut u8 {
+        let next_stub = unsafe { self.stubs.add(self.stub_entries.len() * STUB_SIZE) };
+        *self.stub_entries.entry(target).or_insert_with(|| {
+            unsafe {
+                std::ptr::copy_nonoverlapping(STUB_PREFIX.as_ptr(), next_stub, STUB_PREFIX.len());
+                std::ptr::write_unaligned(next_stub.add(STUB_PREFIX.len()) as *mut u64, target);
+            }
+            next_stub
+        })
+    }
+
+    fn protect(self) {
+        unsafe {
+            let read_only = self.memory.add(self.text_size);
+            let writable = read_only.add(self.read_only_size);
+            for &(addr, size, prot) in &[
+                (self.memory, self.text_size, libc::PROT_READ | libc::PROT_EXEC),
+                (read_only, self.read_only_size, libc::PROT_READ),
+                (writable, self.writable_size, libc::PROT_READ | libc::PROT_WRITE),
+            ] {
+                if size != 0 {
+                    assert_eq!(
+                        libc::mprotect(addr as *mut libc::c_void, size, prot),
+                        0,
+                        "Failed to protect memory of {}",
+                        self.name
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Whether `symbol` is a common symbol, whose storage is allocated by the linker. object doesn't
+/// distinguish `SHN_COMMON` from `SHN_ABS`, but the value of a common symbol is its alignment,
+/// while absolute data symbols don't occur in the objects rustc and C compilers emit.
+fn is_common(symbol: &object::Symbol<'_>) -> bool {
+    symbol.section_index().is_none()
+        && !symbol.is_undefined()
+        && match symbol.kind() {
+            SymbolKind::Common => true,
+            SymbolKind::Data => symbol.address().is_power_of_two(),
+            _ => false,
+        }
+}
+
+/// The priority of the `.init_array` section with the given name. `.init_array.<priority>` sections
+/// run before the plain `.init_array` sections.
+fn init_array_priority(section_name: &str) -> Option<u32> {
+    if section_name == ".init_array" {
+        Some(u32::max_value())
+    } else if section_name.starts_with(".init_array.") {
+        section_name[".init_array.".len()..].parse().ok()
+    } else {
+        None
+    }
+}
+
+fn page_align(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
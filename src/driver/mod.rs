@@ -0,0 +1,125 @@
+use std::any::Any;
+
+use rustc::middle::cstore::EncodedMetadata;
+use rustc::mir::mono::{Linkage as RLinkage, Visibility};
+
+use crate::prelude::*;
+use crate::CodegenMode;
+
+mod aot;
+#[cfg(not(target_arch = "wasm32"))]
+mod jit;
+#[cfg(not(target_arch = "wasm32"))]
+mod jit_debug;
+#[cfg(not(target_arch = "wasm32"))]
+mod jit_linker;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use jit::{JitHandle, JitThread};
+
+pub fn codegen_crate(
+    tcx: TyCtxt<'_>,
+    metadata: EncodedMetadata,
+    need_metadata_module: bool,
+    backend_config: &BackendConfig,
+) -> Box<dyn Any> {
+    tcx.sess.abort_if_errors();
+
+    if backend_config.codegen_mode != CodegenMode::Aot
+        && tcx.sess.crate_types.get().contains(&CrateType::Executable)
+    {
+        #[cfg(not(target_arch = "wasm32"))]
+        let _: ! = jit::run_jit(tcx, backend_config);
+
+        #[cfg(target_arch = "wasm32")]
+        panic!("jit not supported on wasm");
+    }
+
+    aot::run_aot(tcx, metadata, need_metadata_module, backend_config)
+}
+
+fn codegen_mono_items<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    module: &mut Module<impl Backend + 'static>,
+    backend_config: &BackendConfig,
+    debug_context: Option<&mut DebugContext<'tcx>>,
+    unwind_context: Option<&mut UnwindContext>,
+    mono_items: Vec<(MonoItem<'tcx>, (RLinkage, Visibility))>,
+) -> crate::constant::ConstantCx {
+    let mut cx = CodegenCx::new(tcx, module, backend_config, debug_context, unwind_context);
+
+    for &(mono_item, (linkage, visibility)) in &mono_items {
+        match mono_item {
+            MonoItem::Fn(instance) => {
+                let (name, sig) = get_function_name_and_sig(tcx, instance, false);
+                let linkage = crate::linkage::get_clif_linkage(mono_item, linkage, visibility);
+                cx.module.declare_function(&name, linkage, &sig).unwrap();
+            }
+            MonoItem::Static(_) | MonoItem::GlobalAsm(_) => {}
+        }
+    }
+
+    for (mono_item, (linkage, visibility)) in mono_items {
+        crate::unimpl::try_unimpl(tcx, mono_item.to_string(tcx, true), || {
+            let linkage = crate::linkage::get_clif_linkage(mono_item, linkage, visibility);
+            trans_mono_item(&mut cx, mono_item, linkage);
+        });
+    }
+
+    cx.finalize()
+}
+
+fn trans_mono_item<'clif, 'tcx, B: Backend + 'static>(
+    cx: &mut crate::CodegenCx<'clif, 'tcx, B>,
+    mono_item: MonoItem<'tcx>,
+    linkage: Linkage,
+) {
+    let tcx = cx.tcx;
+    match mono_item {
+        MonoItem::Fn(inst) => {
+            let _inst_guard =
+                PrintOnPanic(|| format!("{:?} {}", inst, tcx.symbol_name(inst).name.as_str()));
+            debug_assert!(!inst.substs.needs_infer());
+            let _mir_guard = PrintOnPanic(|| {
+                match inst.def {
+                    InstanceDef::Item(_)
+                    | InstanceDef::DropGlue(_, _)
+                    | InstanceDef::Virtual(_, _) => {
+                        let mut mir = ::std::io::Cursor::new(Vec::new());
+                        crate::rustc_mir::util::write_mir_pretty(
+                            tcx,
+                            Some(inst.def_id()),
+                            &mut mir,
+                        )
+                        .unwrap();
+                        String::from_utf8(mir.into_inner()).unwrap()
+                    }
+                    _ => {
+                        // FIXME fix write_mir_pretty for these instances
+                        format!("{:#?}", tcx.instance_mir(inst.def))
+                    }
+                }
+            });
+
+            crate::base::trans_fn(cx, inst, linkage);
+        }
+        MonoItem::Static(def_id) => {
+            crate::constant::codegen_static(&mut cx.constants_cx, def_id);
+            if let Some(debug_context) = &mut cx.debug_context {
+                debug_context.define_static(def_id);
+            }
+        }
+        MonoItem::GlobalAsm(node_id) => tcx
+            .sess
+            .fatal(&format!("Unimplemented global asm mono item {:?}", node_id)),
+    }
+}
+
+fn time<R>(name: &str, f: impl FnOnce() -> R) -> R {
+    println!("[{}] start", name);
+    let before = std::time::Instant::now();
+    let res = f();
+    let after = std::time::Instant::now();
+    println!("[{}] end time: {:?}", name, after - before);
+    res
+}
@@ -0,0 +1,200 @@
+//! Post-processing of the ELF object files written by faerie, for the few properties of symbols and
+//! sections faerie has no way to express.
+
+use crate::prelude::*;
+
+const SHT_SYMTAB: u32 = 2;
+const SHF_ALLOC: u32 = 0x2;
+const SHF_COMPRESSED: u32 = 0x800;
+const STV_HIDDEN: u8 = 2;
+
+/// Changes to make to an ELF object file after faerie has written it.
+pub struct ElfFixups {
+    /// Symbols which need hidden visibility. Cranelift's `Linkage` has no variant for hidden
+    /// symbols, so rustc's `Visibility::Hidden` can only be applied afterwards.
+    pub hidden_symbols: FxHashSet<String>,
+    /// Sections which start with a compression header and need the `SHF_COMPRESSED` flag, which
+    /// faerie can't set.
+    pub compressed_sections: Vec<String>,
+    /// Sections which are loaded into memory together with the code. faerie only sets
+    /// `SHF_ALLOC` for the sections of functions and data objects, not for custom sections like
+    /// `.eh_frame`.
+    pub alloc_sections: Vec<String>,
+}
+
+impl ElfFixups {
+    pub fn apply(&self, obj: &mut [u8]) {
+        if self.hidden_symbols.is_empty()
+            && self.compressed_sections.is_empty()
+            && self.alloc_sections.is_empty()
+        {
+            return;
+        }
+        let mut elf = ElfFile::parse(obj).expect("faerie wrote an invalid ELF file");
+        hide_symbols(&mut elf, &self.hidden_symbols);
+        add_section_flags(&mut elf, &self.compressed_sections, SHF_COMPRESSED);
+        add_section_flags(&mut elf, &self.alloc_sections, SHF_ALLOC);
+    }
+}
+
+/// A mutable view of an ELF object file.
+struct ElfFile<'a> {
+    data: &'a mut [u8],
+    is_64: bool,
+    is_little_endian: bool,
+}
+
+/// The fields of a section header this module needs, together with the offset of the header
+/// itself.
+struct SectionHeader {
+    header_offset: usize,
+    name: u32,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+}
+
+impl<'a> ElfFile<'a> {
+    fn parse(data: &'a mut [u8]) -> Option<Self> {
+        if data.len() < 0x40 || &data[0..4] != b"\x7fELF" {
+            return None;
+        }
+        let is_64 = data[4] == 2;
+        let is_little_endian = data[5] == 1;
+        Some(ElfFile {
+            data,
+            is_64,
+            is_little_endian,
+        })
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        let bytes = self.data[offset..offset + 2].try_into().unwrap();
+        if self.is_little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        let bytes = self.data[offset..offset + 4].try_into().unwrap();
+        if self.is_little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        let bytes = self.data[offset..offset + 8].try_into().unwrap();
+        if self.is_little_endian {
+            u64::from_le_bytes(bytes)
+        } else {
+            u64::from_be_bytes(bytes)
+        }
+    }
+
+    /// Read a field which is 32 bit in ELF32 and 64 bit in ELF64 files.
+    fn read_word(&self, offset32: usize, offset64: usize) -> usize {
+        if self.is_64 {
+            self.read_u64(offset64) as usize
+        } else {
+            self.read_u32(offset32) as usize
+        }
+    }
+
+    fn section_headers(&self) -> Vec<SectionHeader> {
+        let shoff = self.read_word(0x20, 0x28);
+        let (shentsize, shnum) = if self.is_64 {
+            (self.read_u16(0x3a), self.read_u16(0x3c))
+        } else {
+            (self.read_u16(0x2e), self.read_u16(0x30))
+        };
+        (0..usize::from(shnum))
+            .map(|index| {
+                let header_offset = shoff + index * usize::from(shentsize);
+                SectionHeader {
+                    header_offset,
+                    name: self.read_u32(header_offset),
+                    kind: self.read_u32(header_offset + 4),
+                    offset: self.read_word(header_offset + 16, header_offset + 24),
+                    size: self.read_word(header_offset + 20, header_offset + 32),
+                    link: self.read_u32(header_offset + if self.is_64 { 40 } else { 24 }),
+                }
+            })
+            .collect()
+    }
+
+    /// Read the NUL terminated string at `offset` in the string table `strtab`.
+    fn string(&self, strtab: &SectionHeader, offset: u32) -> &[u8] {
+        let start = strtab.offset + offset as usize;
+        let len = self.data[start..strtab.offset + strtab.size]
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap();
+        &self.data[start..start + len]
+    }
+}
+
+fn hide_symbols(elf: &mut ElfFile<'_>, symbols: &FxHashSet<String>) {
+    if symbols.is_empty() {
+        return;
+    }
+    let sections = elf.section_headers();
+    let symtab = match sections.iter().find(|section| section.kind == SHT_SYMTAB) {
+        Some(symtab) => symtab,
+        None => return,
+    };
+    let strtab = &sections[symtab.link as usize];
+
+    let (entry_size, other_offset) = if elf.is_64 { (24, 5) } else { (16, 13) };
+    let mut hidden = Vec::new();
+    for entry_offset in (symtab.offset..symtab.offset + symtab.size).step_by(entry_size) {
+        let name = elf.string(strtab, elf.read_u32(entry_offset));
+        if symbols.contains(&*String::from_utf8_lossy(name)) {
+            hidden.push(entry_offset + other_offset);
+        }
+    }
+
+    for other_offset in hidden {
+        // The low two bits of `st_other` are the visibility.
+        elf.data[other_offset] = (elf.data[other_offset] & !0x3) | STV_HIDDEN;
+    }
+}
+
+fn add_section_flags(elf: &mut ElfFile<'_>, section_names: &[String], flag: u32) {
+    if section_names.is_empty() {
+        return;
+    }
+    let shstrndx = usize::from(elf.read_u16(if elf.is_64 { 0x3e } else { 0x32 }));
+    let sections = elf.section_headers();
+    let shstrtab = &sections[shstrndx];
+
+    let mut flag_offsets = Vec::new();
+    for section in &sections {
+        let name = elf.string(shstrtab, section.name);
+        if section_names.iter().any(|section_name| section_name.as_bytes() == name) {
+            flag_offsets.push(section.header_offset + 8);
+        }
+    }
+    assert_eq!(flag_offsets.len(), section_names.len());
+
+    // `sh_flags` is 32 bit in ELF32 and 64 bit in ELF64 files, but the flags set here are in the
+    // lowest 32 bits either way.
+    for flags_offset in flag_offsets {
+        let low_offset = if elf.is_64 && !elf.is_little_endian {
+            flags_offset + 4
+        } else {
+            flags_offset
+        };
+        let flags = elf.read_u32(low_offset) | flag;
+        let bytes = if elf.is_little_endian {
+            flags.to_le_bytes()
+        } else {
+            flags.to_be_bytes()
+        };
+        elf.data[low_offset..low_offset + 4].copy_from_slice(&bytes);
+    }
+}
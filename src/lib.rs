@@ -10,19 +10,22 @@ extern crate rustc_data_structures;
 extern crate rustc_driver;
 extern crate rustc_fs_util;
 extern crate rustc_incremental;
+extern crate rustc_interface;
 extern crate rustc_index;
 extern crate rustc_mir;
 extern crate rustc_target;
 extern crate syntax;
+extern crate syntax_pos;
 
 use std::any::Any;
 
-use rustc::dep_graph::DepGraph;
+use rustc::dep_graph::{DepGraph, WorkProduct, WorkProductId};
 use rustc::middle::cstore::{EncodedMetadata, MetadataLoader};
 use rustc::session::config::OutputFilenames;
 use rustc::ty::query::Providers;
 use rustc::util::common::ErrorReported;
 use rustc_codegen_utils::codegen_backend::CodegenBackend;
+use syntax::symbol::Symbol;
 
 use cranelift::codegen::settings;
 
@@ -37,17 +40,21 @@ mod base;
 mod cast;
 mod codegen_i128;
 mod common;
+mod config;
 mod constant;
 mod debuginfo;
 mod discriminant;
 mod driver;
+mod elf;
 mod intrinsics;
 mod linkage;
 mod llvm_intrinsics;
 mod main_shim;
 mod metadata;
 mod num;
+mod perf_map;
 mod pretty_clif;
+mod target_features;
 mod target_features_whitelist;
 mod trap;
 mod unimpl;
@@ -55,6 +62,10 @@ mod unsize;
 mod value_and_place;
 mod vtable;
 
+pub use crate::config::{BackendConfig, CodegenMode, OptLevel};
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::driver::{JitHandle, JitThread};
+
 mod prelude {
     pub use std::any::Any;
     pub use std::collections::{HashMap, HashSet};
@@ -103,7 +114,8 @@ mod prelude {
     pub use crate::base::{trans_operand, trans_place};
     pub use crate::cast::*;
     pub use crate::common::*;
-    pub use crate::debuginfo::{DebugContext, FunctionDebugContext};
+    pub use crate::config::BackendConfig;
+    pub use crate::debuginfo::{DebugContext, FunctionDebugContext, UnwindContext};
     pub use crate::trap::*;
     pub use crate::unimpl::unimpl;
     pub use crate::value_and_place::{CPlace, CPlaceInner, CValue};
@@ -136,28 +148,38 @@ impl Default for Caches<'_> {
 pub struct CodegenCx<'clif, 'tcx, B: Backend + 'static> {
     tcx: TyCtxt<'tcx>,
     module: &'clif mut Module<B>,
+    backend_config: &'clif BackendConfig,
     constants_cx: ConstantCx,
     caches: Caches<'tcx>,
     debug_context: Option<&'clif mut DebugContext<'tcx>>,
+    unwind_context: Option<&'clif mut UnwindContext>,
 }
 
 impl<'clif, 'tcx, B: Backend + 'static> CodegenCx<'clif, 'tcx, B> {
     fn new(
         tcx: TyCtxt<'tcx>,
         module: &'clif mut Module<B>,
+        backend_config: &'clif BackendConfig,
         debug_context: Option<&'clif mut DebugContext<'tcx>>,
+        unwind_context: Option<&'clif mut UnwindContext>,
     ) -> Self {
         CodegenCx {
             tcx,
             module,
+            backend_config,
             constants_cx: ConstantCx::default(),
             caches: Caches::default(),
             debug_context,
+            unwind_context,
         }
     }
 
-    fn finalize(self) {
+    /// Define all data objects referenced by the translated functions. The returned `ConstantCx`
+    /// remembers which data objects have been defined, so it can be reused for later additions to
+    /// the same module.
+    fn finalize(mut self) -> ConstantCx {
         self.constants_cx.finalize(self.tcx, self.module);
+        self.constants_cx
     }
 }
 
@@ -166,6 +188,15 @@ struct CraneliftCodegenBackend;
 impl CodegenBackend for CraneliftCodegenBackend {
     fn init(&self, _sess: &Session) {}
 
+    fn target_features(&self, sess: &Session) -> Vec<Symbol> {
+        let whitelist = target_features_whitelist::target_feature_whitelist(sess);
+        target_features::target_features(sess)
+            .into_iter()
+            .filter(|feature| whitelist.iter().any(|&(name, _)| name == feature.as_str()))
+            .map(|feature| Symbol::intern(&feature))
+            .collect()
+    }
+
     fn metadata_loader(&self) -> Box<dyn MetadataLoader + Sync> {
         Box::new(crate::metadata::CraneliftMetadataLoader)
     }
@@ -208,7 +239,10 @@ impl CodegenBackend for CraneliftCodegenBackend {
     ) -> Box<dyn Any> {
         rustc_codegen_utils::check_for_rustc_errors_attr(tcx);
 
-        let res = driver::codegen_crate(tcx, metadata, need_metadata_module);
+        let backend_config = BackendConfig::from_opts(tcx.sess);
+        tcx.sess.abort_if_errors();
+
+        let res = driver::codegen_crate(tcx, metadata, need_metadata_module, &backend_config);
 
         rustc_incremental::assert_module_sources::assert_module_sources(tcx);
         rustc_codegen_utils::symbol_names_test::report_symbol_names(tcx);
@@ -220,25 +254,29 @@ impl CodegenBackend for CraneliftCodegenBackend {
         &self,
         res: Box<dyn Any>,
         sess: &Session,
-        _dep_graph: &DepGraph,
+        dep_graph: &DepGraph,
         outputs: &OutputFilenames,
     ) -> Result<(), ErrorReported> {
         use rustc_codegen_ssa::back::link::link_binary;
 
-        let codegen_results = *res
-            .downcast::<CodegenResults>()
+        let (codegen_results, work_products) = *res
+            .downcast::<(CodegenResults, FxHashMap<WorkProductId, WorkProduct>)>()
             .expect("Expected CraneliftCodegenBackend's CodegenResult, found Box<Any>");
 
+        rustc::util::common::time(sess, "serialize work products", move || {
+            rustc_incremental::save_work_product_index(sess, &dep_graph, work_products)
+        });
+
         let _timer = sess.prof.generic_activity("link_crate");
 
         rustc::util::common::time(sess, "linking", || {
-            let target_cpu = crate::target_triple(sess).to_string();
+            let target_cpu = crate::target_features::target_cpu(sess);
             link_binary::<crate::archive::ArArchiveBuilder<'_>>(
                 sess,
                 &codegen_results,
                 outputs,
                 &codegen_results.crate_name.as_str(),
-                &target_cpu,
+                target_cpu,
             );
         });
 
@@ -254,7 +292,20 @@ fn default_call_conv(sess: &Session) -> CallConv {
     CallConv::triple_default(&target_triple(sess))
 }
 
-fn build_isa(sess: &Session, enable_pic: bool) -> Box<dyn isa::TargetIsa + 'static> {
+fn build_isa(
+    sess: &Session,
+    backend_config: &BackendConfig,
+    enable_pic: bool,
+) -> Box<dyn isa::TargetIsa + 'static> {
+    build_isa_from_flags(sess, shared_flags(sess, backend_config, enable_pic), &[])
+}
+
+/// The isa independent settings, which are the same for every isa of a session.
+fn shared_flags(
+    sess: &Session,
+    backend_config: &BackendConfig,
+    enable_pic: bool,
+) -> settings::Flags {
     let mut flags_builder = settings::builder();
     if enable_pic {
         flags_builder.enable("is_pic").unwrap();
@@ -265,7 +316,7 @@ fn build_isa(sess: &Session, enable_pic: bool) -> Box<dyn isa::TargetIsa + 'stat
     flags_builder
         .set(
             "enable_verifier",
-            if cfg!(debug_assertions) {
+            if backend_config.enable_verifier {
                 "true"
             } else {
                 "false"
@@ -273,27 +324,35 @@ fn build_isa(sess: &Session, enable_pic: bool) -> Box<dyn isa::TargetIsa + 'stat
         )
         .unwrap();
 
-    // FIXME(CraneStation/cranelift#732) fix LICM in presence of jump tables
-    /*
-    use rustc::session::config::OptLevel;
-    match sess.opts.optimize {
-        OptLevel::No => {
-            flags_builder.set("opt_level", "fastest").unwrap();
-        }
-        OptLevel::Less | OptLevel::Default => {}
-        OptLevel::Aggressive => {
-            flags_builder.set("opt_level", "best").unwrap();
-        }
-        OptLevel::Size | OptLevel::SizeMin => {
-            sess.warn("Optimizing for size is not supported. Just ignoring the request");
-        }
-    }*/
+    let opt_level = backend_config.opt_level(sess);
+    flags_builder.set("opt_level", opt_level.as_str()).unwrap();
+
+    if opt_level == config::OptLevel::Best {
+        // FIXME(CraneStation/cranelift#732) LICM doesn't correctly update jump tables when
+        // inserting a loop pre-header. LICM only runs at opt level best, so only there lower
+        // `br_table` to a tree of branches during legalization instead.
+        flags_builder.set("jump_tables_enabled", "false").unwrap();
+    }
+
+    settings::Flags::new(flags_builder)
+}
 
+/// Build an isa from the shared settings with the given isa flags enabled in addition to the
+/// features enabled for the whole crate. See `target_features::isa_flags_for_features`.
+fn build_isa_from_flags(
+    sess: &Session,
+    flags: settings::Flags,
+    isa_flags: &[&str],
+) -> Box<dyn isa::TargetIsa + 'static> {
     let target_triple = crate::target_triple(sess);
-    let flags = settings::Flags::new(flags_builder);
-    cranelift::codegen::isa::lookup(target_triple)
-        .unwrap()
-        .finish(flags)
+    let mut isa_builder = cranelift::codegen::isa::lookup(target_triple).unwrap();
+    for flag in crate::target_features::global_isa_flags(sess)
+        .iter()
+        .chain(isa_flags)
+    {
+        isa_builder.enable(flag).unwrap();
+    }
+    isa_builder.finish(flags)
 }
 
 /// This is the entrypoint for a hot plugged rustc_codegen_cranelift
@@ -2,13 +2,17 @@ use rustc::mir::mono::{Linkage as RLinkage, MonoItem, Visibility};
 
 use crate::prelude::*;
 
+/// Cranelift has no notion of visibility, so hidden symbols get the linkage of their default
+/// visibility counterparts here. The AOT driver marks them hidden in the object file afterwards,
+/// see `elf::hide_symbols`.
 pub fn get_clif_linkage(mono_item: MonoItem, linkage: RLinkage, visibility: Visibility) -> Linkage {
     match (linkage, visibility) {
         (RLinkage::External, Visibility::Default) => Linkage::Export,
         (RLinkage::Internal, Visibility::Default) => Linkage::Local,
-        // FIXME this should get external linkage, but hidden visibility,
-        // not internal linkage and default visibility
         (RLinkage::External, Visibility::Hidden) => Linkage::Export,
+        // Internal functions of a codegen unit split by target features, see
+        // `aot::split_by_target_features`.
+        (RLinkage::LinkOnceODR, Visibility::Hidden) => Linkage::Preemptible,
         _ => panic!("{:?} = {:?} {:?}", mono_item, linkage, visibility),
     }
 }
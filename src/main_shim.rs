@@ -1,8 +1,17 @@
+use rustc::mir::mono::CodegenUnit;
+
 use crate::prelude::*;
 
 /// Create the `main` function which will initialize the rust runtime and call
 /// users main function.
-pub fn maybe_create_entry_wrapper(tcx: TyCtxt<'_>, module: &mut Module<impl Backend + 'static>) {
+///
+/// When a codegen unit is given, the wrapper is only created if that codegen unit contains the
+/// users main function, so that `main` is defined exactly once across all object files.
+pub fn maybe_create_entry_wrapper<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    module: &mut Module<impl Backend + 'static>,
+    cgu: Option<&CodegenUnit<'tcx>>,
+) {
     use rustc::middle::lang_items::StartFnLangItem;
     use rustc::session::config::EntryFnType;
 
@@ -17,6 +26,13 @@ pub fn maybe_create_entry_wrapper(tcx: TyCtxt<'_>, module: &mut Module<impl Back
         None => return,
     };
 
+    if let Some(cgu) = cgu {
+        let main_instance = Instance::mono(tcx, main_def_id);
+        if !cgu.items().contains_key(&MonoItem::Fn(main_instance)) {
+            return;
+        }
+    }
+
     create_entry_fn(tcx, module, main_def_id, use_start_lang_item);
 
     fn create_entry_fn(
@@ -96,5 +112,6 @@ pub fn maybe_create_entry_wrapper(tcx: TyCtxt<'_>, module: &mut Module<impl Back
             bcx.finalize();
         }
         m.define_function(cmain_func_id, &mut ctx).unwrap();
+        crate::perf_map::record_function("main", &ctx, m.isa());
     }
 }
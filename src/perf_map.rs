@@ -0,0 +1,332 @@
+//! Support for profiling JIT-compiled code with `perf`.
+//!
+//! `perf` can't symbolize code that isn't backed by a file. With `-Cllvm-args=jit-perf=map` the
+//! JIT writes `/tmp/perf-<pid>.map`, which `perf report` uses for addresses it can't resolve
+//! otherwise. `jit-perf=jitdump` additionally writes `/tmp/jit-<pid>.dump` in the jitdump format,
+//! which contains the code bytes and line info of every function. It has to be merged into the
+//! recording using `perf inject --jit` after recording with `perf record -k 1`.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use cranelift::codegen::isa::TargetIsa;
+
+use crate::prelude::*;
+
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+
+thread_local! {
+    /// Only set in JIT mode when profiling support is enabled.
+    static PERF_STATE: RefCell<Option<PerfState>> = RefCell::new(None);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PerfMode {
+    Map,
+    JitDump,
+}
+
+pub struct PerfState {
+    map: BufWriter<File>,
+    jitdump: Option<JitDump>,
+    /// Functions which have been defined, but not yet finalized.
+    pending: Vec<PendingFunction>,
+}
+
+struct PendingFunction {
+    name: String,
+    size: u32,
+    lines: Vec<LineRow>,
+}
+
+struct LineRow {
+    offset: u32,
+    file: String,
+    line: u32,
+}
+
+struct JitDump {
+    file: BufWriter<File>,
+    code_index: u64,
+}
+
+pub fn enable(sess: &Session, mode: PerfMode) {
+    let pid = std::process::id();
+
+    let map = match File::create(format!("/tmp/perf-{}.map", pid)) {
+        Ok(file) => BufWriter::new(file),
+        Err(err) => sess.fatal(&format!("Failed to create perf map: {}", err)),
+    };
+
+    let jitdump = match mode {
+        PerfMode::Map => None,
+        PerfMode::JitDump => match JitDump::new(sess, pid) {
+            Ok(jitdump) => Some(jitdump),
+            Err(err) => sess.fatal(&format!("Failed to create jitdump file: {}", err)),
+        },
+    };
+
+    PERF_STATE.with(|perf_state| {
+        *perf_state.borrow_mut() = Some(PerfState {
+            map,
+            jitdump,
+            pending: Vec::new(),
+        });
+    });
+}
+
+/// Take the state of the current thread, so it can be handed over to the thread translating
+/// functions after a hot reload.
+pub fn take_state() -> Option<PerfState> {
+    PERF_STATE.with(|perf_state| perf_state.borrow_mut().take())
+}
+
+pub fn restore_state(state: Option<PerfState>) {
+    PERF_STATE.with(|perf_state| *perf_state.borrow_mut() = state);
+}
+
+/// Remember the function that was just defined from `context`, so it can be written out once
+/// its address is known.
+pub fn record_function(name: &str, context: &Context, isa: &dyn TargetIsa) {
+    record_function_inner(name, context, isa, |_| Vec::new());
+}
+
+/// Like `record_function`, but also record line info for the jitdump format.
+pub fn record_function_with_lines(
+    tcx: TyCtxt<'_>,
+    name: &str,
+    context: &Context,
+    isa: &dyn TargetIsa,
+    source_info_set: &indexmap::IndexSet<SourceInfo>,
+    default_span: Span,
+) {
+    record_function_inner(name, context, isa, |needs_lines| {
+        if !needs_lines {
+            return Vec::new();
+        }
+
+        let encinfo = isa.encoding_info();
+        let func = &context.func;
+        let mut insts = func
+            .layout
+            .ebbs()
+            .flat_map(|ebb| func.inst_offsets(ebb, &encinfo))
+            .collect::<Vec<_>>();
+        insts.sort_by_key(|&(offset, _inst, _size)| offset);
+
+        let mut lines: Vec<LineRow> = Vec::new();
+        for (offset, inst, _size) in insts {
+            let srcloc = func.srclocs[inst];
+            let span = if srcloc.is_default() {
+                default_span
+            } else {
+                source_info_set
+                    .get_index(srcloc.bits() as usize)
+                    .unwrap()
+                    .span
+            };
+            let loc = tcx.sess.source_map().lookup_char_pos(span.lo());
+            let file = loc.file.name.to_string();
+            let line = loc.line as u32;
+            match lines.last() {
+                Some(last) if last.file == file && last.line == line => {}
+                _ => lines.push(LineRow { offset, file, line }),
+            }
+        }
+        lines
+    });
+}
+
+fn record_function_inner(
+    name: &str,
+    context: &Context,
+    isa: &dyn TargetIsa,
+    lines: impl FnOnce(bool) -> Vec<LineRow>,
+) {
+    PERF_STATE.with(|perf_state| {
+        let mut perf_state = perf_state.borrow_mut();
+        let perf_state = match &mut *perf_state {
+            Some(perf_state) => perf_state,
+            None => return,
+        };
+
+        let encinfo = isa.encoding_info();
+        let func = &context.func;
+        let size = func
+            .layout
+            .ebbs()
+            .flat_map(|ebb| func.inst_offsets(ebb, &encinfo))
+            .map(|(offset, _inst, size)| offset + size)
+            .max()
+            .unwrap_or(0);
+
+        perf_state.pending.push(PendingFunction {
+            name: name.to_string(),
+            size,
+            lines: lines(perf_state.jitdump.is_some()),
+        });
+    });
+}
+
+/// Write out all recorded functions. Must be called after finalizing the module. `lookup`
+/// returns the address of a function by its symbol name.
+pub fn flush(sess: &Session, lookup: impl Fn(&str) -> Option<*const u8>) {
+    PERF_STATE.with(|perf_state| {
+        let mut perf_state = perf_state.borrow_mut();
+        let perf_state = match &mut *perf_state {
+            Some(perf_state) => perf_state,
+            None => return,
+        };
+
+        let res = (|| -> io::Result<()> {
+            for func in perf_state.pending.drain(..) {
+                let addr = match lookup(&func.name) {
+                    Some(addr) => addr,
+                    None => continue,
+                };
+                let name = demangle(&func.name);
+                writeln!(perf_state.map, "{:x} {:x} {}", addr as usize, func.size, name)?;
+                if let Some(jitdump) = &mut perf_state.jitdump {
+                    jitdump.write_function(&name, addr, &func)?;
+                }
+            }
+            perf_state.map.flush()?;
+            if let Some(jitdump) = &mut perf_state.jitdump {
+                jitdump.file.flush()?;
+            }
+            Ok(())
+        })();
+
+        if let Err(err) = res {
+            sess.warn(&format!("Failed to write perf map: {}", err));
+        }
+    });
+}
+
+/// Demangle `name`, keeping suffixes like the one of lazily compiled functions.
+fn demangle(name: &str) -> String {
+    let (symbol, suffix) = match name.find("E.") {
+        Some(idx) => name.split_at(idx + 1),
+        None => (name, ""),
+    };
+    format!("{:#}{}", rustc_demangle::demangle(symbol), suffix)
+}
+
+impl JitDump {
+    fn new(sess: &Session, pid: u32) -> io::Result<Self> {
+        let elf_mach = match crate::target_triple(sess).architecture {
+            target_lexicon::Architecture::X86_64 => 62,
+            target_lexicon::Architecture::I386
+            | target_lexicon::Architecture::I586
+            | target_lexicon::Architecture::I686 => 3,
+            target_lexicon::Architecture::Aarch64 => 183,
+            _ => 0,
+        };
+
+        let file = File::create(format!("/tmp/jit-{}.dump", pid))?;
+
+        // `perf inject` finds the jitdump file through the mmap event of this mapping.
+        unsafe {
+            use std::os::unix::io::AsRawFd;
+            let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+            let marker = libc::mmap(
+                std::ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            );
+            if marker == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let mut file = BufWriter::new(file);
+        file.write_all(&JITDUMP_MAGIC.to_ne_bytes())?;
+        file.write_all(&JITDUMP_VERSION.to_ne_bytes())?;
+        file.write_all(&40u32.to_ne_bytes())?; // header size
+        file.write_all(&(elf_mach as u32).to_ne_bytes())?;
+        file.write_all(&0u32.to_ne_bytes())?; // padding
+        file.write_all(&pid.to_ne_bytes())?;
+        file.write_all(&timestamp().to_ne_bytes())?;
+        file.write_all(&0u64.to_ne_bytes())?; // flags
+
+        Ok(JitDump {
+            file,
+            code_index: 0,
+        })
+    }
+
+    fn write_function(
+        &mut self,
+        name: &str,
+        addr: *const u8,
+        func: &PendingFunction,
+    ) -> io::Result<()> {
+        let timestamp = timestamp();
+
+        // The debug info must precede the code load record it belongs to.
+        if !func.lines.is_empty() {
+            let entries_size = func
+                .lines
+                .iter()
+                .map(|row| 8 + 4 + 4 + row.file.len() + 1)
+                .sum::<usize>();
+            self.write_record_header(JIT_CODE_DEBUG_INFO, 8 + 8 + entries_size, timestamp)?;
+            self.file.write_all(&(addr as u64).to_ne_bytes())?;
+            self.file.write_all(&(func.lines.len() as u64).to_ne_bytes())?;
+            for row in &func.lines {
+                self.file
+                    .write_all(&(addr as u64 + row.offset as u64).to_ne_bytes())?;
+                self.file.write_all(&row.line.to_ne_bytes())?;
+                self.file.write_all(&0u32.to_ne_bytes())?; // discriminator
+                self.file.write_all(row.file.as_bytes())?;
+                self.file.write_all(&[0])?;
+            }
+        }
+
+        let code = unsafe { std::slice::from_raw_parts(addr, func.size as usize) };
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as u32;
+        self.write_record_header(
+            JIT_CODE_LOAD,
+            4 + 4 + 8 + 8 + 8 + 8 + name.len() + 1 + code.len(),
+            timestamp,
+        )?;
+        self.file.write_all(&std::process::id().to_ne_bytes())?;
+        self.file.write_all(&tid.to_ne_bytes())?;
+        self.file.write_all(&(addr as u64).to_ne_bytes())?; // vma
+        self.file.write_all(&(addr as u64).to_ne_bytes())?; // code_addr
+        self.file.write_all(&(code.len() as u64).to_ne_bytes())?;
+        self.file.write_all(&self.code_index.to_ne_bytes())?;
+        self.file.write_all(name.as_bytes())?;
+        self.file.write_all(&[0])?;
+        self.file.write_all(code)?;
+        self.code_index += 1;
+
+        Ok(())
+    }
+
+    fn write_record_header(&mut self, id: u32, body_size: usize, timestamp: u64) -> io::Result<()> {
+        self.file.write_all(&id.to_ne_bytes())?;
+        self.file
+            .write_all(&((4 + 4 + 8 + body_size) as u32).to_ne_bytes())?;
+        self.file.write_all(&timestamp.to_ne_bytes())
+    }
+}
+
+/// `perf record -k 1` uses `CLOCK_MONOTONIC` for its timestamps.
+fn timestamp() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
@@ -195,8 +195,10 @@ impl<'a, 'tcx, B: Backend + 'static> FunctionCx<'_, 'tcx, B> {
     }
 }
 
+/// Write `func` to the `clif_dump_dir` of `backend_config`, which must be set.
 pub fn write_clif_file<'tcx>(
     tcx: TyCtxt<'tcx>,
+    backend_config: &BackendConfig,
     postfix: &str,
     instance: Instance<'tcx>,
     func: &ir::Function,
@@ -206,13 +208,12 @@ pub fn write_clif_file<'tcx>(
     use std::io::Write;
 
     let symbol_name = tcx.symbol_name(instance).name.as_str();
-    let clif_file_name = format!(
-        "{}/{}__{}.{}.clif",
-        concat!(env!("CARGO_MANIFEST_DIR"), "/target/out/clif"),
+    let clif_file_name = backend_config.clif_dump_dir.as_ref().unwrap().join(format!(
+        "{}__{}.{}.clif",
         tcx.crate_name(LOCAL_CRATE),
         symbol_name,
         postfix,
-    );
+    ));
 
     let mut clif = String::new();
     cranelift::codegen::write::decorate_function(
@@ -221,7 +222,9 @@ pub fn write_clif_file<'tcx>(
         &func,
         &DisplayFunctionAnnotations {
             isa: Some(&*crate::build_isa(
-                tcx.sess, true, /* PIC doesn't matter here */
+                tcx.sess,
+                backend_config,
+                true, /* PIC doesn't matter here */
             )),
             value_ranges,
         },
@@ -233,6 +236,12 @@ pub fn write_clif_file<'tcx>(
             let target_triple = crate::target_triple(tcx.sess);
             writeln!(file, "test compile").unwrap();
             writeln!(file, "set is_pic").unwrap();
+            writeln!(
+                file,
+                "set opt_level={}",
+                backend_config.opt_level(tcx.sess).as_str()
+            )
+            .unwrap();
             writeln!(file, "target {}", target_triple).unwrap();
             writeln!(file, "").unwrap();
             file.write(clif.as_bytes()).unwrap();
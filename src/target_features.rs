@@ -0,0 +1,243 @@
+//! Mapping of Rust target features to the ISA flags of Cranelift.
+//!
+//! The features enabled for the whole crate are determined the same way LLVM does: first the
+//! features of `-Ctarget-cpu` (or the default cpu of the target), then the features of the target
+//! spec and finally `-Ctarget-feature`, where later entries override earlier ones. Functions can
+//! enable additional features using `#[target_feature]`.
+
+use crate::prelude::*;
+
+/// The x86 target features which Cranelift can use, together with the corresponding ISA flag.
+const X86_FEATURE_FLAGS: &[(&str, &str)] = &[
+    ("sse3", "has_sse3"),
+    ("ssse3", "has_ssse3"),
+    ("sse4.1", "has_sse41"),
+    ("sse4.2", "has_sse42"),
+    ("popcnt", "has_popcnt"),
+    ("avx", "has_avx"),
+    ("bmi1", "has_bmi1"),
+    ("bmi2", "has_bmi2"),
+    ("lzcnt", "has_lzcnt"),
+];
+
+/// Features implied by enabling another feature. Cranelift doesn't know about these implications,
+/// so `#[target_feature(enable = "avx2")]` needs to enable `has_avx`, `has_sse42`, ... itself.
+const X86_IMPLIED_FEATURES: &[(&str, &str)] = &[
+    ("sse2", "sse"),
+    ("sse3", "sse2"),
+    ("ssse3", "sse3"),
+    ("sse4.1", "ssse3"),
+    ("sse4.2", "sse4.1"),
+    ("avx", "sse4.2"),
+    ("avx2", "avx"),
+    ("fma", "avx"),
+    ("f16c", "avx"),
+];
+
+/// The features of named cpus, excluding features implied by the listed ones.
+const X86_CPU_FEATURES: &[(&str, &[&str])] = &[
+    ("x86-64", &["fxsr", "sse2"]),
+    ("pentium4", &["fxsr", "sse2"]),
+    ("core2", &["fxsr", "ssse3"]),
+    ("penryn", &["fxsr", "sse4.1"]),
+    ("nehalem", &["fxsr", "sse4.2", "popcnt"]),
+    ("corei7", &["fxsr", "sse4.2", "popcnt"]),
+    ("westmere", &["fxsr", "sse4.2", "popcnt"]),
+    ("sandybridge", &["fxsr", "avx", "popcnt"]),
+    ("corei7-avx", &["fxsr", "avx", "popcnt"]),
+    ("ivybridge", &["fxsr", "avx", "f16c", "popcnt"]),
+    ("core-avx-i", &["fxsr", "avx", "f16c", "popcnt"]),
+    ("haswell", &["fxsr", "avx2", "bmi1", "bmi2", "f16c", "fma", "lzcnt", "popcnt"]),
+    ("core-avx2", &["fxsr", "avx2", "bmi1", "bmi2", "f16c", "fma", "lzcnt", "popcnt"]),
+    ("broadwell", &["fxsr", "avx2", "bmi1", "bmi2", "f16c", "fma", "lzcnt", "popcnt"]),
+    ("skylake", &["fxsr", "avx2", "bmi1", "bmi2", "f16c", "fma", "lzcnt", "popcnt"]),
+    ("znver1", &["fxsr", "avx2", "bmi1", "bmi2", "f16c", "fma", "lzcnt", "popcnt"]),
+    ("znver2", &["fxsr", "avx2", "bmi1", "bmi2", "f16c", "fma", "lzcnt", "popcnt"]),
+];
+
+fn is_x86(sess: &Session) -> bool {
+    match crate::target_triple(sess).architecture {
+        target_lexicon::Architecture::X86_64
+        | target_lexicon::Architecture::I386
+        | target_lexicon::Architecture::I586
+        | target_lexicon::Architecture::I686 => true,
+        _ => false,
+    }
+}
+
+/// The cpu given by `-Ctarget-cpu`, or the default cpu of the target.
+pub fn target_cpu(sess: &Session) -> &str {
+    match &sess.opts.cg.target_cpu {
+        Some(cpu) => cpu,
+        None => &sess.target.target.options.cpu,
+    }
+}
+
+/// All target features enabled for the whole crate, using the names of `#[target_feature]`.
+/// Only x86 features are tracked, as Cranelift doesn't have ISA flags for other architectures.
+///
+/// rustc calls this once per session through `CodegenBackend::target_features` and stores the
+/// whitelisted features in `sess.target_features`. Use those instead of calling this again.
+pub fn target_features(sess: &Session) -> Vec<String> {
+    if !is_x86(sess) {
+        return Vec::new();
+    }
+
+    let mut features = Vec::new();
+
+    match target_cpu(sess) {
+        "native" => features.extend(native_features()),
+        // Cpus of 32bit targets without SSE
+        "generic" | "i386" | "i486" | "i586" | "pentium" | "pentiumpro" | "i686" => {}
+        cpu => match X86_CPU_FEATURES.iter().find(|&&(name, _)| name == cpu) {
+            Some(&(_, cpu_features)) => {
+                features.extend(cpu_features.iter().map(|feature| feature.to_string()))
+            }
+            None => sess.warn(&format!(
+                "Unknown target cpu `{}`, only using the features of the target and \
+                 -Ctarget-feature",
+                cpu
+            )),
+        },
+    }
+    add_implied_features(&mut features);
+
+    let feature_args = sess
+        .target
+        .target
+        .options
+        .features
+        .split(',')
+        .chain(sess.opts.cg.target_feature.split(','));
+    for arg in feature_args {
+        let arg = arg.trim();
+        if arg.is_empty() {
+            continue;
+        }
+        let (enable, feature) = match (arg.chars().next().unwrap(), &arg[1..]) {
+            ('+', feature) => (true, feature),
+            ('-', feature) => (false, feature),
+            _ => {
+                sess.warn(&format!(
+                    "Target feature `{}` must start with `+` or `-`, ignoring it",
+                    arg
+                ));
+                continue;
+            }
+        };
+        // `-Ctarget-feature` uses the LLVM names of features.
+        let feature = match feature {
+            "bmi" => "bmi1",
+            feature => feature,
+        };
+
+        if enable {
+            if !features.iter().any(|f| f == feature) {
+                features.push(feature.to_string());
+                add_implied_features(&mut features);
+            }
+        } else {
+            // Disabling a feature also disables all features implying it.
+            features.retain(|f| {
+                let mut implied = vec![f.clone()];
+                add_implied_features(&mut implied);
+                !implied.iter().any(|f| f == feature)
+            });
+        }
+    }
+
+    features.sort();
+    features
+}
+
+/// Detect the features of the host for `-Ctarget-cpu=native`.
+fn native_features() -> Vec<String> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        let mut features = Vec::new();
+        macro_rules! detect {
+            ($($feature:tt),*) => {
+                $(
+                    if is_x86_feature_detected!($feature) {
+                        features.push($feature.to_string());
+                    }
+                )*
+            };
+        }
+        detect!(
+            "fxsr", "sse", "sse2", "sse3", "ssse3", "sse4.1", "sse4.2", "popcnt", "avx", "avx2",
+            "bmi1", "bmi2", "lzcnt", "fma", "f16c"
+        );
+        features
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        Vec::new()
+    }
+}
+
+/// The ISA flags for the features enabled for the whole crate.
+pub fn global_isa_flags(sess: &Session) -> Vec<&'static str> {
+    isa_flags_for_features(
+        sess,
+        sess.target_features
+            .iter()
+            .map(|feature| feature.as_str().to_string()),
+    )
+}
+
+/// The ISA flags corresponding to the target features enabled for `instance` using
+/// `#[target_feature]`, excluding flags which are already enabled for the whole crate. Empty when
+/// none of the features affects the code Cranelift generates.
+pub fn instance_isa_flags(tcx: TyCtxt<'_>, instance: Instance<'_>) -> Vec<&'static str> {
+    let mut flags = match instance.def {
+        InstanceDef::Item(def_id) => isa_flags_for_features(
+            tcx.sess,
+            tcx.codegen_fn_attrs(def_id)
+                .target_features
+                .iter()
+                .map(|feature| feature.as_str().to_string()),
+        ),
+        _ => return Vec::new(),
+    };
+    if !flags.is_empty() {
+        let global_flags = global_isa_flags(tcx.sess);
+        flags.retain(|flag| !global_flags.contains(flag));
+    }
+    flags
+}
+
+/// Map target features to ISA flags. Features which have no corresponding flag don't affect the
+/// code Cranelift generates and are ignored. The result is sorted.
+pub fn isa_flags_for_features(
+    sess: &Session,
+    features: impl Iterator<Item = String>,
+) -> Vec<&'static str> {
+    if !is_x86(sess) {
+        return Vec::new();
+    }
+
+    let mut features = features.collect::<Vec<_>>();
+    add_implied_features(&mut features);
+
+    let mut flags = X86_FEATURE_FLAGS
+        .iter()
+        .filter(|&&(feature, _)| features.iter().any(|f| f == feature))
+        .map(|&(_, flag)| flag)
+        .collect::<Vec<_>>();
+    flags.sort();
+    flags
+}
+
+fn add_implied_features(features: &mut Vec<String>) {
+    let mut i = 0;
+    while i < features.len() {
+        for &(feature, implied) in X86_IMPLIED_FEATURES {
+            if features[i] == feature && !features.iter().any(|f| f == implied) {
+                features.push(implied.to_string());
+            }
+        }
+        i += 1;
+    }
+}
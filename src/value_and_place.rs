@@ -1,3 +1,5 @@
+use cranelift::codegen::ir::ValueLabel;
+
 use crate::prelude::*;
 
 fn codegen_field<'tcx>(
@@ -411,6 +413,7 @@ impl<'tcx> CPlace<'tcx> {
         let addr = match self.inner {
             CPlaceInner::Var(var) => {
                 let data = from.load_scalar(fx);
+                fx.bcx.set_val_label(data, ValueLabel::from_u32(var.as_u32()));
                 fx.bcx.def_var(mir_var(var), data);
                 return;
             }
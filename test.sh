@@ -12,13 +12,37 @@ fi
 
 source config.sh
 
+if [[ "$CHANNEL" == "debug" ]]; then
+    RUSTC="$RUSTC -Cllvm-args=clif-dump-dir=$(pwd)/target/out/clif"
+fi
+
 jit() {
     if [[ `uname` == 'Darwin' ]]; then
         # FIXME(#671) `dlsym` returns "symbol not found" for existing symbols on macOS.
         echo "[JIT] $1 (Ignored on macOS)"
     else
         echo "[JIT] $1"
-        SHOULD_RUN=1 $RUSTC --crate-type bin -Cprefer-dynamic $2
+        $RUSTC --crate-type bin -Cprefer-dynamic $2 -Cllvm-args="mode=jit $3"
+    fi
+}
+
+jit_lazy() {
+    if [[ `uname` == 'Darwin' ]]; then
+        # FIXME(#671) `dlsym` returns "symbol not found" for existing symbols on macOS.
+        echo "[JIT-lazy] $1 (Ignored on macOS)"
+    else
+        echo "[JIT-lazy] $1"
+        $RUSTC --crate-type bin -Cprefer-dynamic $2 -Cllvm-args="mode=lazy-jit $3"
+    fi
+}
+
+jit_static() {
+    if [[ `uname` == 'Darwin' ]]; then
+        # Loading rlibs in JIT mode is only supported for ELF.
+        echo "[JIT-static] $1 (Ignored on macOS)"
+    else
+        echo "[JIT-static] $1"
+        $RUSTC --crate-type bin $2 -Cllvm-args="mode=jit $3"
     fi
 }
 
@@ -31,7 +55,8 @@ $RUSTC example/mini_core.rs --crate-name mini_core --crate-type lib,dylib
 echo "[BUILD] example"
 $RUSTC example/example.rs --crate-type lib
 
-JIT_ARGS="abc bcd" jit mini_core_hello_world example/mini_core_hello_world.rs
+jit mini_core_hello_world example/mini_core_hello_world.rs "jit-arg=abc jit-arg='b cd'"
+jit_static mini_core_hello_world example/mini_core_hello_world.rs "jit-arg=abc jit-arg=bcd"
 
 echo "[AOT] mini_core_hello_world"
 $RUSTC example/mini_core_hello_world.rs --crate-name mini_core_hello_world --crate-type bin
@@ -49,6 +74,71 @@ $RUSTC example/alloc_example.rs --crate-type bin
 ./target/out/alloc_example
 
 jit std_example example/std_example.rs
+jit_lazy std_example example/std_example.rs
+jit_lazy jit_threads example/jit_threads.rs
+jit_static std_example example/std_example.rs
+jit_static jit_threads example/jit_threads.rs
+jit_static env_args example/env_args.rs "jit-arg=abc jit-arg='b cd'"
+
+if [[ `uname` == 'Darwin' ]]; then
+    echo "[JIT] std_example jit-perf=map|jitdump (Ignored on macOS)"
+else
+    # The program runs in the rustc process, whose pid is in the names of the files.
+    echo "[JIT] std_example jit-perf=map"
+    $RUSTC --crate-type bin -Cprefer-dynamic example/std_example.rs \
+        -Cllvm-args="mode=jit jit-perf=map" &
+    pid=$!
+    wait $pid
+    grep -qE '^[0-9a-f]+ [0-9a-f]+ std_example::main$' /tmp/perf-$pid.map
+    rm /tmp/perf-$pid.map
+
+    echo "[JIT-lazy] std_example jit-perf=jitdump"
+    $RUSTC --crate-type bin -Cprefer-dynamic example/std_example.rs \
+        -Cllvm-args="mode=lazy-jit jit-perf=jitdump" &
+    pid=$!
+    wait $pid
+    # Lazily translated functions get a suffix.
+    grep -qE '^[0-9a-f]+ [0-9a-f]+ std_example::main\.lazy_jit\.0$' /tmp/perf-$pid.map
+    # The header starts with the magic number `JiTD` and every function is in a code load record.
+    [[ $(head -c 4 /tmp/jit-$pid.dump | od -An -tx4 | tr -d ' ') == "4a695444" ]]
+    grep -qa 'std_example::main\.lazy_jit\.0' /tmp/jit-$pid.dump
+    rm /tmp/perf-$pid.map /tmp/jit-$pid.dump
+fi
+
+if [[ `uname` == 'Darwin' ]]; then
+    echo "[JIT] jit_handle (Ignored on macOS)"
+else
+    echo "[JIT] jit_handle"
+    # The example is a rustc driver itself, so it runs without the flags for the rustc
+    # invocations of this script and with the libraries of the toolchain.
+    cargo_flags=""
+    if [[ "$CHANNEL" == "release" ]]; then
+        cargo_flags="--release"
+    fi
+    env -u RUSTFLAGS -u LD_LIBRARY_PATH cargo run $cargo_flags --example jit_handle -- \
+        --sysroot build_sysroot/sysroot --crate-type bin -Cprefer-dynamic -Cpanic=abort \
+        --out-dir target/out example/jit_handle_target.rs
+fi
+
+if [[ `uname` == 'Darwin' ]]; then
+    # FIXME(#671) `dlsym` returns "symbol not found" for existing symbols on macOS.
+    echo "[JIT-lazy] hot_reload (Ignored on macOS)"
+else
+    echo "[JIT-lazy] hot_reload"
+    cp example/hot_reload.rs target/out/hot_reload.rs
+    rm -f target/out/hot_reload.ready
+    touch target/out/hot_reload.trigger
+    $RUSTC --crate-type bin -Cprefer-dynamic target/out/hot_reload.rs \
+        -Cllvm-args="mode=lazy-jit jit-reload-trigger=target/out/hot_reload.trigger jit-arg=target/out/hot_reload.ready" &
+    jit_pid=$!
+    while [[ ! -f target/out/hot_reload.ready ]]; do kill -0 $jit_pid; sleep 0.1; done
+    sed -i 's/const VERSION: u32 = 1;/const VERSION: u32 = 2;/' target/out/hot_reload.rs
+    touch target/out/hot_reload.trigger
+    wait $jit_pid
+fi
+
+jit test_harness "--test example/test_harness.rs" "jit-arg=--test-threads jit-arg=1 jit-arg=string_formatting"
+jit_lazy test_harness "--test example/test_harness.rs" "jit-arg=string_formatting"
 
 echo "[AOT] dst_field_align"
 $RUSTC example/dst-field-align.rs -Zmir-opt-level=2 --crate-name dst_field_align --crate-type bin
@@ -58,8 +148,171 @@ echo "[AOT] std_example"
 $RUSTC example/std_example.rs --crate-type bin
 ./target/out/std_example
 
+echo "[AOT] std_example -Cincremental"
+rm -rf target/out/std_example_incr.d
+for session in 1 2; do
+    $RUSTC example/std_example.rs --crate-name std_example_incr --crate-type bin \
+        -Cincremental=target/out/std_example_incr.d -Zincremental-info > target/out/std_example_incr.txt
+    cp target/out/std_example_incr target/out/std_example_incr.$session
+done
+# Nothing changed, so the second session reuses every codegen unit and links the same executable.
+grep -qE 'Re-using ([0-9]+) out of \1 codegen units' target/out/std_example_incr.txt
+cmp target/out/std_example_incr.1 target/out/std_example_incr.2
+./target/out/std_example_incr
+
+echo "[AOT] std_example -Ctarget-cpu=native"
+$RUSTC example/std_example.rs --crate-name std_example_native --crate-type bin -Ctarget-cpu=native
+./target/out/std_example_native
+
+echo "[AOT] target_features"
+$RUSTC example/target_features.rs --crate-type bin -Ctarget-cpu=nehalem \
+    -Ctarget-feature=-popcnt,+lzcnt,-ssse3
+./target/out/target_features
+if [[ `uname` != 'Darwin' ]]; then
+    objdump -d target/out/target_features > target/out/target_features.txt
+    if awk '/<target_features_popcnt>:/,/^$/' target/out/target_features.txt | grep -q popcnt; then
+        echo "popcnt used even though it was disabled"
+        exit 1
+    fi
+    awk '/<target_features_lzcnt>:/,/^$/' target/out/target_features.txt | grep -q lzcnt
+fi
+
+if [[ `uname` == 'Darwin' ]]; then
+    # macOS debuginfo doesn't work yet (see #303)
+    echo "[AOT] debuginfo (Ignored on macOS)"
+else
+    echo "[AOT] debuginfo"
+    $RUSTC example/debuginfo.rs --crate-type bin -g
+    ./target/out/debuginfo
+
+    echo "[AOT] debuginfo debuginfo-compression=zlib"
+    $RUSTC example/debuginfo.rs --crate-name debuginfo_zlib --crate-type bin -g \
+        -Cllvm-args=debuginfo-compression=zlib
+    ./target/out/debuginfo_zlib
+
+    echo "[AOT] debuginfo debuginfo-compression=zlib object file"
+    $RUSTC example/debuginfo.rs --crate-name debuginfo_zlib_obj --crate-type bin --emit obj -g \
+        -Ccodegen-units=1 -Cllvm-args=debuginfo-compression=zlib
+    # The compressed sections keep their name and get the `C` (`SHF_COMPRESSED`) flag.
+    readelf -S --wide target/out/debuginfo_zlib_obj.o \
+        | grep -E '\.debug_info +PROGBITS .* [A-Z]*C[A-Z]* +[0-9]+ +[0-9]+ +[0-9]+$' >/dev/null
+
+    for version in 3 5; do
+        echo "[AOT] debuginfo dwarf-version=$version"
+        $RUSTC example/debuginfo.rs --crate-name debuginfo_v$version --crate-type bin -g \
+            -Cllvm-args=dwarf-version=$version
+        ./target/out/debuginfo_v$version
+    done
+
+    echo "[AOT] debuginfo -Zmir-opt-level=2"
+    $RUSTC example/debuginfo.rs --crate-name debuginfo_inline --crate-type bin -g -Zmir-opt-level=2
+    ./target/out/debuginfo_inline
+
+    echo "[AOT] debuginfo --remap-path-prefix"
+    # Only the object file, the sysroot linked into the executable isn't remapped.
+    $RUSTC "$(pwd)/example/debuginfo.rs" --crate-name debuginfo_remap --crate-type bin \
+        --emit obj -g -Ccodegen-units=1 --remap-path-prefix="$(pwd)=/remapped"
+
+    if command -v llvm-dwarfdump >/dev/null; then
+        for bin in debuginfo debuginfo_v3 debuginfo_v5 debuginfo_inline; do
+            llvm-dwarfdump --verify ./target/out/$bin >/dev/null
+        done
+        # `add_one` is inlined at line 16 of `main`.
+        llvm-dwarfdump --debug-info ./target/out/debuginfo_inline \
+            | grep -A4 DW_TAG_inlined_subroutine \
+            | grep -A2 'DW_AT_abstract_origin.*"add_one"' > target/out/debuginfo_inline.txt
+        grep -q 'DW_AT_call_file.*debuginfo.rs' target/out/debuginfo_inline.txt
+        grep -q 'DW_AT_call_line.*(16)' target/out/debuginfo_inline.txt
+        # Both the compilation directory and the source file are remapped. Code expanded from
+        # macros like `assert_eq!` is attributed to the invocation instead of a `<... macros>` file.
+        llvm-dwarfdump --debug-info --debug-line target/out/debuginfo_remap.o \
+            > target/out/debuginfo_remap.txt
+        grep -q 'DW_AT_comp_dir.*"/remapped"' target/out/debuginfo_remap.txt
+        grep -q '"/remapped/example"' target/out/debuginfo_remap.txt
+        grep -q 'name: "debuginfo.rs"' target/out/debuginfo_remap.txt
+        # Functions instantiated from the sysroot keep the paths the sysroot was built with.
+        if grep -qF -e "\"$(pwd)\"" -e "$(pwd)/example" -e 'name: "<' \
+            target/out/debuginfo_remap.txt; then
+            echo "Unmapped path in debuginfo of debuginfo_remap"
+            exit 1
+        fi
+
+        # The line table marks the end of prologues and the start of epilogues. A new
+        # column on the same line isn't a new statement.
+        llvm-dwarfdump --debug-line target/out/debuginfo_remap.o > target/out/debuginfo_line.txt
+        grep -qE ' is_stmt prologue_end$' target/out/debuginfo_line.txt
+        grep -qE ' epilogue_begin$' target/out/debuginfo_line.txt
+        grep -qE '^0x[0-9a-f]+( +[0-9]+){5,6} *$' target/out/debuginfo_line.txt
+    else
+        echo "[AOT] debuginfo llvm-dwarfdump checks (Skipped, llvm-dwarfdump not found)"
+    fi
+
+    if command -v gdb >/dev/null; then
+        for bin in debuginfo debuginfo_zlib; do
+            gdb -batch -ex "print/x $bin::STATIC" ./target/out/$bin | grep -q '= 0x12345678'
+        done
+
+        echo "[AOT] debuginfo gdb locals"
+        gdb -batch -ex 'break debuginfo::locals' -ex run -ex 'print arg' \
+            -ex 'break debuginfo.rs:25' -ex continue -ex 'print local' -ex 'print on_stack' \
+            ./target/out/debuginfo > target/out/debuginfo_locals.txt
+        # The breakpoint is placed after the prologue, on the first line of the body.
+        grep -q '^Breakpoint 1, debuginfo::locals (arg=7) at .*debuginfo.rs:23$' \
+            target/out/debuginfo_locals.txt
+        grep -q '^\$1 = 7$' target/out/debuginfo_locals.txt
+        grep -q '^\$2 = 14$' target/out/debuginfo_locals.txt
+        grep -q '^\$3 = \[7, 7, 7, 7\]$' target/out/debuginfo_locals.txt
+
+        echo "[AOT] debuginfo gdb namespaces"
+        gdb -batch -ex 'print/x debuginfo::inner::NESTED' -ex 'info functions inner::generic' \
+            -ex 'break debuginfo::inner::generic<u64>' -ex run -ex bt \
+            ./target/out/debuginfo > target/out/debuginfo_namespaces.txt
+        grep -q '^\$1 = 0x87654321$' target/out/debuginfo_namespaces.txt
+        grep -qF 'fn debuginfo::inner::generic<u64>(' target/out/debuginfo_namespaces.txt
+        grep -qE '^#0 .*debuginfo::inner::generic<u64> ' target/out/debuginfo_namespaces.txt
+        grep -qE '^#1 .* debuginfo::main ' target/out/debuginfo_namespaces.txt
+
+        echo "[AOT] call_frames gdb backtrace"
+        $RUSTC example/call_frames.rs --crate-type bin -g
+        gdb -batch -ex 'break call_frames::break_here' -ex run -ex bt ./target/out/call_frames \
+            > target/out/call_frames.txt
+        grep -qE '^#1 .* call_frames::inner ' target/out/call_frames.txt
+        grep -qE '^#2 .* call_frames::outer ' target/out/call_frames.txt
+        grep -qE '^#3 .* call_frames::main ' target/out/call_frames.txt
+
+        echo "[JIT] call_frames gdb backtrace"
+        # Debug rustc itself instead of the rustup proxy, which runs it as a child process. gdb
+        # finds the JIT-compiled functions through `__jit_debug_register_code`.
+        gdb -batch -ex 'set breakpoint pending on' -ex 'break call_frames::break_here' -ex run \
+            -ex bt --args "$(rustc --print sysroot)/bin/rustc" ${RUSTC#rustc} --crate-type bin \
+            -Cprefer-dynamic example/call_frames.rs -Cllvm-args=mode=jit \
+            > target/out/call_frames_jit.txt
+        grep -qE '^#0 .*call_frames::break_here ' target/out/call_frames_jit.txt
+        grep -qE '^#1 .* call_frames::inner ' target/out/call_frames_jit.txt
+        grep -qE '^#2 .* call_frames::outer ' target/out/call_frames_jit.txt
+        grep -qE '^#3 .* call_frames::main ' target/out/call_frames_jit.txt
+    else
+        echo "[AOT] debuginfo gdb checks (Skipped, gdb not found)"
+    fi
+fi
+
 echo "[BUILD] mod_bench"
-$RUSTC example/mod_bench.rs --crate-type bin
+# The clif dumps record the Cranelift optimization level selected by `-Copt-level`.
+rm -rf target/out/clif_opt_level
+mkdir -p target/out/clif_opt_level
+$RUSTC example/mod_bench.rs --crate-type bin \
+    -Cllvm-args=clif-dump-dir=target/out/clif_opt_level
+$RUSTC example/mod_bench.rs --crate-name mod_bench_opt --crate-type bin -O \
+    -Cllvm-args=clif-dump-dir=target/out/clif_opt_level
+$RUSTC example/mod_bench.rs --crate-name mod_bench_size --crate-type bin -Copt-level=s \
+    -Cllvm-args=clif-dump-dir=target/out/clif_opt_level 2> target/out/mod_bench_size.txt
+grep -q 'set opt_level=fastest' target/out/clif_opt_level/mod_bench__*.opt.clif
+grep -q 'set opt_level=best' target/out/clif_opt_level/mod_bench_opt__*.opt.clif
+grep -q 'set opt_level=default' target/out/clif_opt_level/mod_bench_size__*.opt.clif
+grep -q "warning: Cranelift can't optimize for size" target/out/mod_bench_size.txt
+
+echo "[BENCH] mod_bench -Copt-level=0 vs -O"
+hyperfine --runs ${RUN_RUNS:-10} ./target/out/mod_bench ./target/out/mod_bench_opt
 
 # FIXME linker gives multiple definitions error on Linux
 #echo "[BUILD] sysroot in release mode"